@@ -0,0 +1,73 @@
+//! Publish throughput of `KafkaBackend` with and without the metadata cache.
+//!
+//! Needs a broker with topic auto-creation enabled:
+//!
+//!   NODE_STREAM_BENCH_BROKER=127.0.0.1:9092 cargo bench --bench metadata_cache
+//!
+//! A zero metadata TTL reloads the cluster metadata before every send, as
+//! the backend did before it cached metadata.
+
+use node_stream::backend::{KafkaBackend, KafkaBackendConfig, NodeStreamBackend, NodeStreamRecord};
+use node_stream::types::NodeStreamTopic;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const RECORDS: usize = 2_000;
+const RECORD_BYTES: usize = 1_024;
+
+fn publish(host_addr: SocketAddr, metadata_ttl: Duration) -> f64 {
+    let mut backend = KafkaBackend::with_config(
+        host_addr,
+        KafkaBackendConfig {
+            metadata_ttl,
+            ..Default::default()
+        },
+    );
+    let topic = NodeStreamTopic::new(format!(
+        "node-stream-bench-{}",
+        hex::encode(rand::random::<[u8; 4]>())
+    ));
+    let record = || NodeStreamRecord {
+        key: None,
+        value: vec![0xab; RECORD_BYTES],
+        partition: None,
+    };
+    // Creates the topic, so it is not part of the measurement
+    backend
+        .publish(&topic, record())
+        .expect("unable to publish");
+
+    let start = Instant::now();
+    for _ in 0..RECORDS {
+        backend
+            .publish(&topic, record())
+            .expect("unable to publish");
+    }
+    RECORDS as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let host_addr: SocketAddr = match std::env::var("NODE_STREAM_BENCH_BROKER") {
+        Ok(addr) => addr
+            .parse()
+            .expect("NODE_STREAM_BENCH_BROKER is not an address"),
+        Err(_) => {
+            println!("NODE_STREAM_BENCH_BROKER is not set, skipping");
+            return;
+        }
+    };
+    let uncached = publish(host_addr, Duration::ZERO);
+    let cached = publish(host_addr, KafkaBackendConfig::default().metadata_ttl);
+    println!(
+        "metadata reloaded on every send: {:>10.0} records/s",
+        uncached
+    );
+    println!(
+        "metadata cached:                 {:>10.0} records/s",
+        cached
+    );
+    println!(
+        "speedup:                         {:>10.1}x",
+        cached / uncached
+    );
+}
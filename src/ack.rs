@@ -0,0 +1,262 @@
+use crate::backend::NodeStreamPartitionOffset;
+use crate::types::NodeStreamTopic;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Debug, Default)]
+struct PartitionAcks {
+    /// Offsets handed out and not acked yet.
+    pending: BTreeSet<i64>,
+    /// Highest offset read from the partition.
+    delivered: Option<i64>,
+    committed: Option<i64>,
+    /// Bumped by every seek, so handles from before it no longer ack anything.
+    generation: u64,
+}
+
+impl PartitionAcks {
+    /// Last offset such that it and everything before it is acked.
+    fn ackable(&self) -> Option<i64> {
+        match self.pending.first() {
+            Some(lowest) => Some(lowest - 1).filter(|o| *o >= 0),
+            None => self.delivered,
+        }
+    }
+}
+
+/// Tracks acks of messages handed out by a consumer in explicit ack mode, and
+/// of chunks waiting for the rest of their message in either mode.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct AckTracker {
+    partitions: Arc<Mutex<BTreeMap<(String, i32), PartitionAcks>>>,
+}
+
+impl AckTracker {
+    fn partitions(&self) -> MutexGuard<'_, BTreeMap<(String, i32), PartitionAcks>> {
+        self.partitions.lock().expect("ack tracker lock poisoned")
+    }
+
+    /// Records that `offset` was read. Unless the returned handle is acked,
+    /// nothing from `offset` on gets committed.
+    pub(crate) fn deliver(
+        &self,
+        topic: &NodeStreamTopic,
+        partition: i32,
+        offset: i64,
+    ) -> NodeStreamAckHandle {
+        self.skip(topic, partition, offset);
+        let mut partitions = self.partitions();
+        let acks = partitions.entry((topic.to_raw(), partition)).or_default();
+        acks.pending.insert(offset);
+        NodeStreamAckHandle {
+            tracker: self.clone(),
+            topic: topic.clone(),
+            offsets: vec![DeliveredOffset {
+                partition,
+                offset,
+                generation: acks.generation,
+            }],
+        }
+    }
+
+    /// Records that `offset` was read but needs no ack, e.g. control records.
+    pub(crate) fn skip(&self, topic: &NodeStreamTopic, partition: i32, offset: i64) {
+        let mut partitions = self.partitions();
+        let acks = partitions.entry((topic.to_raw(), partition)).or_default();
+        acks.delivered = acks.delivered.max(Some(offset));
+    }
+
+    fn ack(&self, topic: &NodeStreamTopic, delivered: &DeliveredOffset) {
+        if let Some(acks) = self
+            .partitions()
+            .get_mut(&(topic.to_raw(), delivered.partition))
+        {
+            if acks.generation == delivered.generation {
+                acks.pending.remove(&delivered.offset);
+            }
+        }
+    }
+
+    pub(crate) fn has_pending(&self, topic: &NodeStreamTopic) -> bool {
+        self.partitions()
+            .iter()
+            .any(|((t, _), acks)| *t == topic.topic && !acks.pending.is_empty())
+    }
+
+    /// Offsets of `topic` which became committable since the last `mark_committed`.
+    pub(crate) fn committable(&self, topic: &NodeStreamTopic) -> Vec<NodeStreamPartitionOffset> {
+        self.partitions()
+            .iter()
+            .filter(|((t, _), _)| *t == topic.topic)
+            .filter_map(|((_, partition), acks)| {
+                let offset = acks.ackable().filter(|o| Some(*o) > acks.committed)?;
+                Some(NodeStreamPartitionOffset {
+                    partition: *partition,
+                    offset,
+                })
+            })
+            .collect()
+    }
+
+    /// Forgets what was read, acked and committed from `topic`, after
+    /// seeking it. Messages read before are no longer waited for, and acking
+    /// them has no effect.
+    pub(crate) fn reset_positions(&self, topic: &NodeStreamTopic) {
+        for ((t, _), acks) in self.partitions().iter_mut() {
+            if *t == topic.topic {
+                acks.pending.clear();
+                acks.delivered = None;
+                acks.committed = None;
+                acks.generation += 1;
+            }
+        }
+    }
+
+    pub(crate) fn mark_committed(
+        &self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) {
+        let mut partitions = self.partitions();
+        for o in offsets {
+            if let Some(acks) = partitions.get_mut(&(topic.to_raw(), o.partition)) {
+                acks.committed = acks.committed.max(Some(o.offset));
+            }
+        }
+    }
+}
+
+/// Acks a message read by a consumer in explicit ack mode. Its offset is
+/// committed on a later poll once every earlier message of its partition is
+/// acked too. Acking more than once has no effect.
+#[derive(Clone)]
+pub struct NodeStreamAckHandle {
+    tracker: AckTracker,
+    topic: NodeStreamTopic,
+    /// Every record the message was read from, more than one for chunked
+    /// messages.
+    offsets: Vec<DeliveredOffset>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DeliveredOffset {
+    partition: i32,
+    offset: i64,
+    generation: u64,
+}
+
+impl NodeStreamAckHandle {
+    pub fn ack(&self) {
+        for delivered in &self.offsets {
+            self.tracker.ack(&self.topic, delivered);
+        }
+    }
+
+    /// Combines the handles of the records a message was read from into one.
+    pub(crate) fn merge(handles: Vec<Self>) -> Option<Self> {
+        let mut handles = handles.into_iter();
+        let mut merged = handles.next()?;
+        for handle in handles {
+            merged.offsets.extend(handle.offsets);
+        }
+        Some(merged)
+    }
+}
+
+impl std::fmt::Debug for NodeStreamAckHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeStreamAckHandle")
+            .field("topic", &self.topic)
+            .field(
+                "offsets",
+                &self
+                    .offsets
+                    .iter()
+                    .map(|o| (o.partition, o.offset))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offsets(tracker: &AckTracker, topic: &NodeStreamTopic) -> Vec<(i32, i64)> {
+        tracker
+            .committable(topic)
+            .into_iter()
+            .map(|o| (o.partition, o.offset))
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order_acks_commit_up_to_the_first_unacked() {
+        let topic = NodeStreamTopic::new("acks".to_string());
+        let tracker = AckTracker::default();
+        let handles = (0..5)
+            .map(|offset| tracker.deliver(&topic, 0, offset))
+            .collect::<Vec<_>>();
+        let other = tracker.deliver(&topic, 1, 0);
+
+        handles[2].ack();
+        handles[4].ack();
+        assert_eq!(offsets(&tracker, &topic), vec![]);
+        handles[0].ack();
+        assert_eq!(offsets(&tracker, &topic), vec![(0, 0)]);
+        tracker.mark_committed(&topic, &tracker.committable(&topic));
+        assert_eq!(offsets(&tracker, &topic), vec![]);
+
+        handles[1].ack();
+        handles[1].ack();
+        other.ack();
+        assert_eq!(offsets(&tracker, &topic), vec![(0, 2), (1, 0)]);
+        handles[3].ack();
+        assert!(!tracker.has_pending(&topic));
+        assert_eq!(offsets(&tracker, &topic), vec![(0, 4), (1, 0)]);
+
+        // Records needing no ack move the position on once everything before is acked
+        tracker.skip(&topic, 0, 5);
+        assert_eq!(offsets(&tracker, &topic), vec![(0, 5), (1, 0)]);
+    }
+
+    #[test]
+    fn merged_handles_ack_every_chunk() {
+        let topic = NodeStreamTopic::new("acks".to_string());
+        let tracker = AckTracker::default();
+        let chunks = (0..3)
+            .map(|offset| tracker.deliver(&topic, 0, offset))
+            .collect::<Vec<_>>();
+        let message = NodeStreamAckHandle::merge(chunks).unwrap();
+        assert_eq!(offsets(&tracker, &topic), vec![]);
+        message.ack();
+        assert_eq!(offsets(&tracker, &topic), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn seek_drops_pending_acks() {
+        let topic = NodeStreamTopic::new("acks".to_string());
+        let tracker = AckTracker::default();
+        let handles = (0..4)
+            .map(|offset| tracker.deliver(&topic, 0, offset))
+            .collect::<Vec<_>>();
+        handles[0].ack();
+        tracker.mark_committed(&topic, &tracker.committable(&topic));
+
+        // Seek back to offset 0 with 1..3 unacked
+        tracker.reset_positions(&topic);
+        assert!(!tracker.has_pending(&topic));
+        assert_eq!(offsets(&tracker, &topic), vec![]);
+
+        let redelivered = tracker.deliver(&topic, 0, 0);
+        tracker.deliver(&topic, 0, 1);
+        // Handles from before the seek ack nothing
+        handles[1].ack();
+        assert_eq!(offsets(&tracker, &topic), vec![]);
+        redelivered.ack();
+        assert_eq!(offsets(&tracker, &topic), vec![(0, 0)]);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::backend::{
+    KafkaBackend, NodeStreamBackend, NodeStreamTopicConfig, NodeStreamTopicMetadata,
+};
+use crate::types::{
+    NodeStreamAdminError, NodeStreamBackendError, NodeStreamPerEpochTopic, NodeStreamTopic,
+};
+use std::net::SocketAddr;
+
+/// Manages the topics of `NodeStreamPerEpochTopic` families.
+pub struct NodeStreamAdmin<B: NodeStreamBackend = KafkaBackend> {
+    backend: B,
+}
+
+impl NodeStreamAdmin<KafkaBackend> {
+    pub fn new(host_addr: SocketAddr) -> Self {
+        Self::with_backend(KafkaBackend::new(host_addr))
+    }
+}
+
+impl<B: NodeStreamBackend> NodeStreamAdmin<B> {
+    pub fn with_backend(backend: B) -> Self {
+        Self { backend }
+    }
+
+    /// Creates `epoch`'s topic unless it already exists.
+    pub fn create_epoch_topic<
+        T: NodeStreamPerEpochTopic<D, M>,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        epoch: u64,
+        topic: &T,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<NodeStreamTopic, NodeStreamAdminError> {
+        let topic = topic.topic_for_epoch(epoch);
+        self.backend.create_topic(&topic, config).map_err(|err| {
+            NodeStreamAdminError::UnableToCreateTopic {
+                topic: topic.clone(),
+                err,
+            }
+        })?;
+        Ok(topic)
+    }
+
+    /// Returns the partitions, leaders and high watermarks of `epoch`'s
+    /// topic, or `None` if it does not exist.
+    pub fn describe_epoch_topic<
+        T: NodeStreamPerEpochTopic<D, M>,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        epoch: u64,
+        topic: &T,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamAdminError> {
+        let topic = topic.topic_for_epoch(epoch);
+        self.backend
+            .topic_metadata(&topic)
+            .map_err(|err| NodeStreamAdminError::UnableToDescribeTopic { topic, err })
+    }
+
+    /// Returns the existing topics of the family with their epoch, ordered by epoch.
+    pub fn list_epoch_topics<
+        T: NodeStreamPerEpochTopic<D, M>,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        topic: &T,
+    ) -> Result<Vec<(u64, NodeStreamTopic)>, NodeStreamAdminError> {
+        list_epoch_topics(&mut self.backend, topic)
+            .map_err(|err| NodeStreamAdminError::UnableToListTopics { err })
+    }
+
+    /// Returns the epochs of the family which have a topic, in ascending order.
+    pub fn available_epochs<
+        T: NodeStreamPerEpochTopic<D, M>,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        topic: &T,
+    ) -> Result<Vec<u64>, NodeStreamAdminError> {
+        Ok(self
+            .list_epoch_topics(topic)?
+            .into_iter()
+            .map(|(epoch, _)| epoch)
+            .collect())
+    }
+
+    /// Returns the highest epoch of the family which has a topic.
+    pub fn latest_epoch<
+        T: NodeStreamPerEpochTopic<D, M>,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        topic: &T,
+    ) -> Result<Option<u64>, NodeStreamAdminError> {
+        Ok(self.available_epochs(topic)?.last().copied())
+    }
+
+    pub fn delete_epoch_topic<
+        T: NodeStreamPerEpochTopic<D, M>,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        epoch: u64,
+        topic: &T,
+    ) -> Result<(), NodeStreamAdminError> {
+        let topic = topic.topic_for_epoch(epoch);
+        self.backend
+            .delete_topic(&topic)
+            .map_err(|err| NodeStreamAdminError::UnableToDeleteTopic { topic, err })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+/// Existing topics of `topic`'s family with their epoch, ordered by epoch.
+pub(crate) fn list_epoch_topics<
+    B: NodeStreamBackend,
+    T: NodeStreamPerEpochTopic<D, M>,
+    D: std::fmt::Debug,
+    M: std::fmt::Debug,
+>(
+    backend: &mut B,
+    topic: &T,
+) -> Result<Vec<(u64, NodeStreamTopic)>, NodeStreamBackendError> {
+    let mut topics = backend
+        .list_topics()?
+        .into_iter()
+        .filter_map(|t| Some((topic.epoch_for_topic(&t)?, t)))
+        .collect::<Vec<_>>();
+    topics.sort_unstable_by_key(|(epoch, _)| *epoch);
+    Ok(topics)
+}
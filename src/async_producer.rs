@@ -0,0 +1,459 @@
+use crate::backend::{
+    KafkaBackend, NodeStreamBackend, NodeStreamDeliveryReceipt, NodeStreamRecord,
+};
+use crate::producer::{
+    encode_payload, payload_receipt, publish_with_retry, NodeStreamProducerConfig,
+    NodeStreamProducerMetrics, PAYLOAD_SIZE_LIMIT,
+};
+use crate::retry::RetryPolicy;
+use crate::types::{
+    NodeStreamPerEpochTopic, NodeStreamProducerError, NodeStreamTopic, NodeStreamUserPayload,
+};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct AsyncNodeStreamProducerConfig {
+    /// Number of payloads which can be queued before `send` waits.
+    pub queue_capacity: usize,
+    pub batch_max_records: usize,
+    pub batch_max_bytes: usize,
+    /// How long the first payload of a batch waits for others to join it.
+    pub linger: Duration,
+    pub producer: NodeStreamProducerConfig,
+}
+
+impl Default for AsyncNodeStreamProducerConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 10_000,
+            batch_max_records: 500,
+            batch_max_bytes: PAYLOAD_SIZE_LIMIT as usize,
+            linger: Duration::from_millis(5),
+            producer: NodeStreamProducerConfig::default(),
+        }
+    }
+}
+
+type DeliveryResult = Result<NodeStreamDeliveryReceipt, NodeStreamProducerError>;
+
+struct Delivery {
+    topic: NodeStreamTopic,
+    /// More than one for chunked payloads.
+    records: Vec<NodeStreamRecord>,
+    done: oneshot::Sender<DeliveryResult>,
+}
+
+enum Command {
+    Send(Delivery),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Resolves once the payload was published, to where it was written, or
+/// failed to publish.
+#[must_use = "dropping the delivery does not cancel the send, but its result is lost"]
+pub struct NodeStreamDelivery {
+    rx: oneshot::Receiver<DeliveryResult>,
+}
+
+impl Future for NodeStreamDelivery {
+    type Output = DeliveryResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|r| r.unwrap_or(Err(NodeStreamProducerError::ProducerClosed)))
+    }
+}
+
+/// Producer which queues payloads and publishes them in batches from a
+/// background task. Must be created from within a tokio runtime.
+///
+/// Clones share the same queue and background task, which stops once every
+/// clone is dropped and the queue is drained.
+#[derive(Clone)]
+pub struct AsyncNodeStreamProducer {
+    sender: mpsc::Sender<Command>,
+    producer: Arc<NodeStreamProducerConfig>,
+    metrics: NodeStreamProducerMetrics,
+}
+
+impl AsyncNodeStreamProducer {
+    pub fn new(host_addr: SocketAddr, config: AsyncNodeStreamProducerConfig) -> Self {
+        Self::with_backend(KafkaBackend::new(host_addr), config)
+    }
+
+    pub fn with_backend<B: NodeStreamBackend + 'static>(
+        backend: B,
+        config: AsyncNodeStreamProducerConfig,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let producer = Arc::new(config.producer.clone());
+        tokio::spawn(run_batcher(backend, receiver, config));
+        Self {
+            sender,
+            producer,
+            metrics: NodeStreamProducerMetrics::default(),
+        }
+    }
+
+    /// Serializes and enqueues `payload`, waiting for room if the queue is
+    /// full. The returned delivery resolves once the batch holding the payload
+    /// was published.
+    pub async fn send<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &self,
+        epoch: u64,
+        topic: T,
+        payload: &NodeStreamUserPayload<D, M>,
+    ) -> Result<NodeStreamDelivery, NodeStreamProducerError> {
+        let (command, delivery) = self.prepare(epoch, &topic, payload)?;
+        self.sender
+            .send(command)
+            .await
+            .map_err(|_| NodeStreamProducerError::ProducerClosed)?;
+        Ok(delivery)
+    }
+
+    /// Like `send`, but fails with `QueueFull` instead of waiting for room.
+    pub fn try_send<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &self,
+        epoch: u64,
+        topic: T,
+        payload: &NodeStreamUserPayload<D, M>,
+    ) -> Result<NodeStreamDelivery, NodeStreamProducerError> {
+        let (command, delivery) = self.prepare(epoch, &topic, payload)?;
+        self.sender.try_send(command).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => NodeStreamProducerError::QueueFull,
+            mpsc::error::TrySendError::Closed(_) => NodeStreamProducerError::ProducerClosed,
+        })?;
+        Ok(delivery)
+    }
+
+    /// Waits until every payload enqueued before this call was handed to the backend.
+    pub async fn flush(&self) -> Result<(), NodeStreamProducerError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(Command::Flush(tx))
+            .await
+            .map_err(|_| NodeStreamProducerError::ProducerClosed)?;
+        rx.await
+            .map_err(|_| NodeStreamProducerError::ProducerClosed)
+    }
+
+    pub fn metrics(&self) -> &NodeStreamProducerMetrics {
+        &self.metrics
+    }
+
+    fn prepare<T: NodeStreamPerEpochTopic<D, M>, D: std::fmt::Debug, M: std::fmt::Debug>(
+        &self,
+        epoch: u64,
+        topic: &T,
+        payload: &NodeStreamUserPayload<D, M>,
+    ) -> Result<(Command, NodeStreamDelivery), NodeStreamProducerError> {
+        let (topic, records) =
+            encode_payload(&self.producer, &self.metrics, epoch, topic, payload)?;
+        let (done, rx) = oneshot::channel();
+        Ok((
+            Command::Send(Delivery {
+                topic,
+                records,
+                done,
+            }),
+            NodeStreamDelivery { rx },
+        ))
+    }
+}
+
+async fn run_batcher<B: NodeStreamBackend + 'static>(
+    mut backend: B,
+    mut receiver: mpsc::Receiver<Command>,
+    config: AsyncNodeStreamProducerConfig,
+) {
+    while let Some(command) = receiver.recv().await {
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+        let mut flushed = vec![];
+        let mut next = Some(command);
+        let deadline = Instant::now() + config.linger;
+
+        while let Some(command) = next.take() {
+            match command {
+                Command::Send(delivery) => {
+                    batch_bytes += delivery
+                        .records
+                        .iter()
+                        .map(|r| r.value.len())
+                        .sum::<usize>();
+                    batch.push(delivery);
+                }
+                // Ship whatever we have right away
+                Command::Flush(done) => {
+                    flushed.push(done);
+                    break;
+                }
+            }
+            if batch.len() >= config.batch_max_records || batch_bytes >= config.batch_max_bytes {
+                break;
+            }
+            next = tokio::time::timeout_at(deadline, receiver.recv())
+                .await
+                .ok()
+                .flatten();
+        }
+
+        // The backend does blocking IO, keep it off the runtime's worker threads
+        let retry = config.producer.retry.clone();
+        backend = match tokio::task::spawn_blocking(move || {
+            publish_batch(&mut backend, &retry, batch);
+            backend
+        })
+        .await
+        {
+            Ok(backend) => backend,
+            Err(err) => {
+                tracing::error!("node stream producer batch task failed: {}", err);
+                return;
+            }
+        };
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Publishes `batch` grouped by topic, keeping the order payloads were sent in.
+fn publish_batch<B: NodeStreamBackend>(backend: &mut B, retry: &RetryPolicy, batch: Vec<Delivery>) {
+    let mut by_topic: Vec<(NodeStreamTopic, Vec<Delivery>)> = vec![];
+    for delivery in batch {
+        match by_topic
+            .iter_mut()
+            .find(|(topic, _)| topic.topic == delivery.topic.topic)
+        {
+            Some((_, deliveries)) => deliveries.push(delivery),
+            None => by_topic.push((delivery.topic.clone(), vec![delivery])),
+        }
+    }
+
+    for (topic, deliveries) in by_topic {
+        let (waiters, records): (Vec<_>, Vec<_>) = deliveries
+            .into_iter()
+            .map(|d| ((d.records.len(), d.done), d.records))
+            .unzip();
+        let records = records.into_iter().flatten().collect();
+        let mut result = publish_with_retry(backend, retry, &topic, records).map(|r| r.into_iter());
+        for (num_records, done) in waiters {
+            let receipt = match &mut result {
+                Ok(results) => payload_receipt(results.by_ref().take(num_records).collect()),
+                // Every payload of the request failed the same way
+                Err(err) => Err(err.duplicate()),
+            };
+            let _ = done
+                .send(receipt.map_err(|err| NodeStreamProducerError::MessageSendFailed { err }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryBroker;
+    use crate::test_utils::{payload, read_all, FaultyBackend, PublishFault, TestTopic};
+    use crate::types::NodeStreamBackendError;
+    use futures::FutureExt;
+    use std::collections::BTreeMap;
+    use std::ops::Range;
+
+    fn topic() -> TestTopic {
+        TestTopic::new("async-producer-test-")
+    }
+
+    fn producer(
+        backend: &FaultyBackend,
+        config: AsyncNodeStreamProducerConfig,
+    ) -> AsyncNodeStreamProducer {
+        AsyncNodeStreamProducer::with_backend(backend.clone(), config)
+    }
+
+    async fn send_all(
+        producer: &AsyncNodeStreamProducer,
+        data: Range<u64>,
+    ) -> Vec<NodeStreamDelivery> {
+        let mut deliveries = vec![];
+        for data in data {
+            deliveries.push(producer.send(0, topic(), &payload(data)).await.unwrap());
+        }
+        deliveries
+    }
+
+    async fn offsets(deliveries: Vec<NodeStreamDelivery>) -> Vec<i64> {
+        let mut offsets = vec![];
+        for delivery in deliveries {
+            offsets.push(delivery.await.unwrap().offset);
+        }
+        offsets
+    }
+
+    #[tokio::test]
+    async fn full_batches_ship_without_lingering() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        let producer = producer(
+            &backend,
+            AsyncNodeStreamProducerConfig {
+                batch_max_records: 3,
+                linger: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+        let deliveries = send_all(&producer, 0..6).await;
+        let offsets = tokio::time::timeout(Duration::from_secs(10), offsets(deliveries))
+            .await
+            .unwrap();
+        assert_eq!(offsets, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(backend.batches(), vec![3, 3]);
+    }
+
+    #[tokio::test]
+    async fn batches_linger_for_more_payloads() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        let linger = Duration::from_millis(50);
+        let producer = producer(
+            &backend,
+            AsyncNodeStreamProducerConfig {
+                linger,
+                ..Default::default()
+            },
+        );
+        let start = Instant::now();
+        let deliveries = send_all(&producer, 0..2).await;
+        assert_eq!(offsets(deliveries).await, vec![0, 1]);
+        assert!(start.elapsed() >= linger);
+        assert_eq!(backend.batches(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn full_queues_hold_sends_back() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        let producer = producer(
+            &backend,
+            AsyncNodeStreamProducerConfig {
+                queue_capacity: 1,
+                linger: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+        // The batcher does not get to run before the test yields
+        let first = producer.try_send(0, topic(), &payload(0)).unwrap();
+        assert!(matches!(
+            producer.try_send(0, topic(), &payload(1)),
+            Err(NodeStreamProducerError::QueueFull)
+        ));
+        assert!(producer
+            .send(0, topic(), &payload(1))
+            .now_or_never()
+            .is_none());
+
+        // Waiting lets the batcher make room
+        let second = producer.send(0, topic(), &payload(1)).await.unwrap();
+        assert_eq!(offsets(vec![first, second]).await, vec![0, 1]);
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn flush_and_shutdown_publish_every_queued_payload() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        let config = AsyncNodeStreamProducerConfig {
+            linger: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let producer = producer(&backend, config);
+        let flushed = send_all(&producer, 0..3).await;
+        tokio::time::timeout(Duration::from_secs(10), producer.flush())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0, 1, 2]);
+
+        let dropped = send_all(&producer, 3..5).await;
+        drop(producer);
+        let offsets = tokio::time::timeout(Duration::from_secs(10), offsets(dropped))
+            .await
+            .unwrap();
+        assert_eq!(offsets, vec![3, 4]);
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0, 1, 2, 3, 4]);
+        assert!(flushed
+            .into_iter()
+            .all(|d| d.now_or_never().unwrap().is_ok()));
+    }
+
+    #[tokio::test]
+    async fn publish_errors_reach_each_delivery() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        let config = AsyncNodeStreamProducerConfig {
+            producer: NodeStreamProducerConfig {
+                retry: RetryPolicy::none(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let producer = producer(&backend, config);
+        backend.fail_next(PublishFault::Records(BTreeMap::from([(
+            1,
+            NodeStreamBackendError::NotSubscribed,
+        )])));
+        backend.fail_next(PublishFault::Request {
+            err: NodeStreamBackendError::UnableToPublish {
+                topic: topic().topic_for_epoch(0),
+                err: std::io::Error::from(std::io::ErrorKind::ConnectionReset).into(),
+            },
+            published: false,
+        });
+
+        // One batch failing on its second record, then one failing as a whole
+        let mut deliveries = send_all(&producer, 0..2).await;
+        producer.flush().await.unwrap();
+        deliveries.extend(send_all(&producer, 2..4).await);
+        let mut results = vec![];
+        for delivery in deliveries {
+            results.push(delivery.await);
+        }
+
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            &results[1],
+            Err(err @ NodeStreamProducerError::MessageSendFailed {
+                err: NodeStreamBackendError::NotSubscribed,
+            }) if !err.is_retryable()
+        ));
+        for result in &results[2..] {
+            assert!(matches!(
+                result,
+                Err(err @ NodeStreamProducerError::MessageSendFailed {
+                    err: NodeStreamBackendError::UnableToPublish { .. },
+                }) if err.is_retryable()
+            ));
+        }
+        assert_eq!(backend.batches(), vec![2, 2]);
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0]);
+    }
+}
@@ -0,0 +1,987 @@
+use crate::kafka_admin::{self, KafkaAdminClient, KafkaTopicResult};
+use crate::retry::RetryPolicy;
+use crate::types::{NodeStreamBackendError, NodeStreamTopic};
+use kafka::client::KafkaClient;
+use kafka::consumer::{Consumer, FetchOffset};
+use kafka::error::KafkaCode;
+use kafka::producer::{Producer, Record};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// ================= Records ===========================
+
+/// A serialized record handed to a backend for publishing.
+#[derive(Debug, Clone)]
+pub struct NodeStreamRecord {
+    /// Records with the same key are kept in order on the same partition.
+    pub key: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+    /// Partition to write the record to, instead of the one the backend
+    /// would pick.
+    pub partition: Option<i32>,
+}
+
+impl NodeStreamRecord {
+    /// Checks `self` can be published by a backend whose topics have a
+    /// single partition.
+    pub(crate) fn ensure_single_partition(&self) -> Result<(), NodeStreamBackendError> {
+        match self.partition {
+            Some(partition) if partition != 0 => Err(NodeStreamBackendError::Unsupported {
+                operation: format!("publishing to partition {}", partition),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A record read back from a backend subscription.
+#[derive(Debug, Clone)]
+pub struct NodeStreamFetchedRecord {
+    pub topic: NodeStreamTopic,
+    pub partition: i32,
+    pub offset: i64,
+    pub value: Vec<u8>,
+}
+
+/// Where a published record was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeStreamDeliveryReceipt {
+    pub topic: NodeStreamTopic,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Outcome of publishing one record.
+pub type NodeStreamPublishResult = Result<NodeStreamDeliveryReceipt, NodeStreamBackendError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeStreamPartitionOffset {
+    pub partition: i32,
+    pub offset: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeStreamPartitionMetadata {
+    pub partition: i32,
+    pub leader: Option<String>,
+    /// Offset the next record written to this partition will get.
+    pub high_watermark: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeStreamTopicMetadata {
+    pub topic: NodeStreamTopic,
+    pub partitions: Vec<NodeStreamPartitionMetadata>,
+}
+
+/// Settings for topics created through `NodeStreamBackend::create_topic`.
+/// Settings left to `None` use the backend's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeStreamTopicConfig {
+    pub partitions: Option<i32>,
+    pub replication_factor: Option<i16>,
+}
+
+impl NodeStreamTopicConfig {
+    /// Checks `self` can be applied by a backend whose topics have a single,
+    /// unreplicated partition.
+    pub(crate) fn ensure_single_partition(&self) -> Result<(), NodeStreamBackendError> {
+        if self.partitions.is_some_and(|p| p != 1) {
+            return Err(NodeStreamBackendError::Unsupported {
+                operation: "topics with more than one partition".to_string(),
+            });
+        }
+        if self.replication_factor.is_some_and(|r| r != 1) {
+            return Err(NodeStreamBackendError::Unsupported {
+                operation: "replicated topics".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Where a subscription starts reading.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NodeStreamStartPosition {
+    #[default]
+    Earliest,
+    /// Only records written after the subscription was made.
+    Latest,
+    /// Offset of the next record to read, per partition. Partitions missing
+    /// from the map start from the earliest offset.
+    Offsets(BTreeMap<i32, i64>),
+    /// This many records before the end of each partition.
+    FromEnd(u64),
+    /// First record written at or after this time, in milliseconds since the
+    /// unix epoch. Only supported by backends which keep record timestamps.
+    Timestamp(i64),
+}
+
+// ================= Backend ===========================
+
+/// Transport used by `NodeStreamProducer` and `NodeStreamConsumer`.
+///
+/// A backend instance serves either a producer or a consumer. Consumers call
+/// `subscribe` once before polling; calling it again replaces the subscription.
+pub trait NodeStreamBackend: Send {
+    fn publish(
+        &mut self,
+        topic: &NodeStreamTopic,
+        record: NodeStreamRecord,
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamBackendError>;
+
+    /// Publishes several records to the same topic, returning the outcome of
+    /// each in the same order. Fails as a whole only if nothing could be
+    /// published. Backends which can ship the records in a single request
+    /// should override this.
+    fn publish_batch(
+        &mut self,
+        topic: &NodeStreamTopic,
+        records: Vec<NodeStreamRecord>,
+    ) -> Result<Vec<NodeStreamPublishResult>, NodeStreamBackendError> {
+        Ok(records
+            .into_iter()
+            .map(|record| self.publish(topic, record))
+            .collect())
+    }
+
+    /// Subscribes `group_id` to `topic`. The group resumes from its committed
+    /// offsets, and `start` only applies if it has none on this topic.
+    fn subscribe(
+        &mut self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        start: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError>;
+
+    /// Moves the current subscription to `position`, committing it for the
+    /// subscribed group.
+    fn seek(
+        &mut self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError>;
+
+    /// Next offset the subscription reads on each partition of its topic.
+    /// Backends which cannot tell once records were polled return where the
+    /// subscription resumed: the group's committed offsets, or where the
+    /// start position put it.
+    fn positions(&mut self) -> Result<Vec<NodeStreamPartitionOffset>, NodeStreamBackendError>;
+
+    /// Returns the records fetched since the last poll. Fetching does not
+    /// commit anything; call `commit` once the records are handled.
+    fn poll(&mut self) -> Result<Vec<NodeStreamFetchedRecord>, NodeStreamBackendError>;
+
+    /// Commits the given offsets for the subscribed group. Each offset is the
+    /// last record consumed on its partition.
+    fn commit(
+        &mut self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) -> Result<(), NodeStreamBackendError>;
+
+    /// Returns `None` if the topic does not exist. Never creates the topic.
+    fn topic_metadata(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamBackendError>;
+
+    /// Creates `topic` unless it already exists. Fails with `Unsupported` if
+    /// the backend cannot apply `config`.
+    fn create_topic(
+        &mut self,
+        topic: &NodeStreamTopic,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamBackendError>;
+
+    /// Deletes `topic` along with its records and committed offsets.
+    fn delete_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError>;
+
+    /// Returns every existing topic.
+    fn list_topics(&mut self) -> Result<Vec<NodeStreamTopic>, NodeStreamBackendError>;
+}
+
+// ================= Kafka ===========================
+
+#[derive(Debug, Clone)]
+pub struct KafkaBackendConfig {
+    /// How long cluster metadata loaded by the producer is trusted. Sends to
+    /// topics known to it within that time skip the metadata round trip.
+    /// Metadata is also reloaded after errors showing it went stale, such as
+    /// an unknown partition or a leader change.
+    pub metadata_ttl: Duration,
+    /// Applied to metadata refreshes before publishing, including waiting
+    /// for the broker to auto-create a topic, and to topic creation and
+    /// deletion while the controller is moving.
+    pub metadata_retry: RetryPolicy,
+    /// Bounds each step of creating or deleting a topic, including how long
+    /// the controller waits for the change to complete.
+    pub admin_timeout: Duration,
+}
+
+impl Default for KafkaBackendConfig {
+    fn default() -> Self {
+        Self {
+            metadata_ttl: Duration::from_secs(30),
+            metadata_retry: RetryPolicy {
+                max_attempts: 100,
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(1),
+                jitter: 0.2,
+                deadline: Some(Duration::from_secs(10)),
+            },
+            admin_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Kafka backend. The underlying kafka producer and consumer are created on
+/// first use, so cloning a `KafkaBackend` only copies its configuration.
+///
+/// The backend picks the partition of each record itself, so it can tell
+/// which offset each one got: keyed records go to the partition Kafka's
+/// default partitioner would pick, the murmur2 hash of their key, so other
+/// Kafka clients writing the same keys agree with it. Records without a key
+/// are spread round-robin.
+pub struct KafkaBackend {
+    host_addr: SocketAddr,
+    config: KafkaBackendConfig,
+    kafka_producer: Option<Producer>,
+    kafka_consumer: Option<Consumer>,
+    subscription: Option<KafkaSubscription>,
+    next_partition: i32,
+    metadata_loaded_at: Option<Instant>,
+}
+
+impl KafkaBackend {
+    pub fn new(host_addr: SocketAddr) -> Self {
+        Self::with_config(host_addr, KafkaBackendConfig::default())
+    }
+
+    pub fn with_config(host_addr: SocketAddr, config: KafkaBackendConfig) -> Self {
+        Self {
+            host_addr,
+            config,
+            kafka_producer: None,
+            kafka_consumer: None,
+            subscription: None,
+            next_partition: 0,
+            metadata_loaded_at: None,
+        }
+    }
+
+    /// Like `new`, but connects to the cluster right away, so an unreachable
+    /// broker is reported here rather than by the first publish.
+    pub fn connect(host_addr: SocketAddr) -> Result<Self, NodeStreamBackendError> {
+        let mut backend = Self::new(host_addr);
+        backend.producer()?;
+        Ok(backend)
+    }
+
+    pub fn host_addr(&self) -> SocketAddr {
+        self.host_addr
+    }
+
+    fn hosts(&self) -> Vec<String> {
+        vec![self.host_addr.to_string()]
+    }
+
+    fn create_producer(&self) -> Result<Producer, NodeStreamBackendError> {
+        Producer::from_hosts(self.hosts())
+            .create()
+            .map_err(|err| NodeStreamBackendError::UnableToConnect { err: err.into() })
+    }
+
+    fn producer(&mut self) -> Result<&mut Producer, NodeStreamBackendError> {
+        if self.kafka_producer.is_none() {
+            self.kafka_producer = Some(self.create_producer()?);
+        }
+        Ok(self.kafka_producer.as_mut().unwrap())
+    }
+
+    fn consumer(&mut self) -> Result<&mut Consumer, NodeStreamBackendError> {
+        self.kafka_consumer
+            .as_mut()
+            .ok_or(NodeStreamBackendError::NotSubscribed)
+    }
+
+    fn create_consumer(
+        &self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        fallback_offset: FetchOffset,
+    ) -> Result<Consumer, NodeStreamBackendError> {
+        Consumer::from_hosts(self.hosts())
+            .with_group(group_id.to_string())
+            .with_topic(topic.to_raw())
+            .with_fallback_offset(fallback_offset)
+            .create()
+            .map_err(|err| NodeStreamBackendError::UnableToConnect { err: err.into() })
+    }
+
+    /// A standalone client with metadata loaded for `topic`.
+    fn topic_client(&self, topic: &NodeStreamTopic) -> Result<KafkaClient, NodeStreamBackendError> {
+        let mut client = KafkaClient::new(self.hosts());
+        client.load_metadata(&[topic.to_raw()]).map_err(|err| {
+            NodeStreamBackendError::UnableToLoadMetadata {
+                topic: Some(topic.clone()),
+                err: err.into(),
+            }
+        })?;
+        Ok(client)
+    }
+
+    /// Commits the offsets `position` resolves to for `group_id`, so the next
+    /// consumer created for that group starts there.
+    fn commit_position(
+        &self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let topic_str = topic.to_raw();
+        let mut client = self.topic_client(topic)?;
+        let mut fetch = |offset| {
+            client
+                .fetch_topic_offsets(&topic_str, offset)
+                .map(|offsets| {
+                    offsets
+                        .into_iter()
+                        .map(|o| (o.partition, o.offset))
+                        .collect::<BTreeMap<_, _>>()
+                })
+                .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                    topic: Some(topic.clone()),
+                    err: err.into(),
+                })
+        };
+        let earliest = fetch(FetchOffset::Earliest)?;
+        let targets = match position {
+            NodeStreamStartPosition::Earliest => earliest,
+            NodeStreamStartPosition::Latest => fetch(FetchOffset::Latest)?,
+            NodeStreamStartPosition::Timestamp(ms) => {
+                let found = fetch(FetchOffset::ByTime(*ms))?;
+                offsets_at_time(found, fetch(FetchOffset::Latest)?)
+            }
+            NodeStreamStartPosition::FromEnd(n) => fetch(FetchOffset::Latest)?
+                .into_iter()
+                .map(|(p, end)| {
+                    let start = earliest.get(&p).copied().unwrap_or_default();
+                    (p, (end - *n as i64).max(start))
+                })
+                .collect(),
+            NodeStreamStartPosition::Offsets(offsets) => earliest
+                .into_iter()
+                .map(|(p, start)| (p, offsets.get(&p).copied().unwrap_or(start)))
+                .collect(),
+        };
+        for (partition, offset) in targets {
+            client
+                .commit_offset(group_id, &topic_str, partition, offset)
+                .map_err(|err| NodeStreamBackendError::UnableToCommit {
+                    topic: topic.clone(),
+                    err: err.into(),
+                })?;
+        }
+        Ok(())
+    }
+
+    fn has_committed_offsets(
+        &self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+    ) -> Result<bool, NodeStreamBackendError> {
+        let offsets = self
+            .topic_client(topic)?
+            .fetch_group_topic_offsets(group_id, &topic.to_raw())
+            .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                topic: Some(topic.clone()),
+                err: err.into(),
+            })?;
+        // Kafka reports -1 for partitions the group never committed
+        Ok(offsets.iter().any(|o| o.offset >= 0))
+    }
+
+    fn partition_for(&mut self, key: Option<&[u8]>, num_partitions: i32) -> i32 {
+        match key.filter(|key| !key.is_empty()) {
+            Some(key) => ((murmur2(key) & 0x7fff_ffff) % num_partitions as u32) as i32,
+            None => {
+                let partition = self.next_partition % num_partitions;
+                self.next_partition = partition + 1;
+                partition
+            }
+        }
+    }
+
+    fn validate_topic(&self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        topic
+            .validate()
+            .map_err(|_| NodeStreamBackendError::InvalidTopicName {
+                topic: topic.clone(),
+            })
+    }
+
+    /// Sends a CreateTopics or DeleteTopics request, retrying it as
+    /// `metadata_retry` allows while the controller moves or times out.
+    /// Cached metadata is dropped afterwards, as the topic changed.
+    fn topic_request(
+        &mut self,
+        topic: &NodeStreamTopic,
+        mut request: impl FnMut(&mut KafkaAdminClient, &str) -> std::io::Result<KafkaTopicResult>,
+    ) -> Result<KafkaTopicResult, NodeStreamBackendError> {
+        let topic_str = topic.to_raw();
+        let mut client = KafkaAdminClient::new(self.host_addr, self.config.admin_timeout);
+        let result = self
+            .config
+            .metadata_retry
+            .run(NodeStreamBackendError::is_retryable, || {
+                let result = request(&mut client, &topic_str)
+                    .map_err(|err| NodeStreamBackendError::Io { err })?;
+                if kafka_admin::is_retryable_code(result.error_code) {
+                    return Err(NodeStreamBackendError::TopicRequestRejected {
+                        topic: topic.clone(),
+                        error_code: result.error_code,
+                        message: result.message,
+                    });
+                }
+                Ok(result)
+            });
+        self.metadata_loaded_at = None;
+        result
+    }
+
+    /// Refreshes metadata unless it is fresh and knows `topic`, and waits for
+    /// the broker to auto-create `topic` for as long as `metadata_retry` allows.
+    /// This will only loop the first time a topic is hit.
+    fn ensure_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        self.validate_topic(topic)?;
+        let topic_str = topic.to_raw();
+
+        let fresh = self
+            .metadata_loaded_at
+            .is_some_and(|at| at.elapsed() < self.config.metadata_ttl);
+        if fresh && self.producer()?.client().topics().contains(&topic_str) {
+            return Ok(());
+        }
+
+        let policy = self.config.metadata_retry.clone();
+        policy.run(NodeStreamBackendError::is_retryable, || {
+            // Make sure up to date
+            self.producer()?
+                .client_mut()
+                .load_metadata_all()
+                .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                    topic: None,
+                    err: err.into(),
+                })?;
+            self.metadata_loaded_at = Some(Instant::now());
+            if self.producer()?.client().topics().contains(&topic_str) {
+                return Ok(());
+            }
+
+            // Add the topic
+            self.producer()?
+                .client_mut()
+                .load_metadata(&[&topic_str])
+                .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                    topic: Some(topic.clone()),
+                    err: err.into(),
+                })?;
+            // try to initialize again
+            self.kafka_producer = Some(self.create_producer()?);
+            match self.producer()?.client().topics().contains(&topic_str) {
+                true => Ok(()),
+                false => Err(NodeStreamBackendError::TopicNotCreated {
+                    topic: topic.clone(),
+                }),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct KafkaSubscription {
+    topic: NodeStreamTopic,
+    group_id: String,
+    /// Where partitions the group never committed are read from.
+    fallback_offset: FetchOffset,
+}
+
+impl Clone for KafkaBackend {
+    fn clone(&self) -> Self {
+        Self::with_config(self.host_addr, self.config.clone())
+    }
+}
+
+/// Kafka's murmur2, as used by its default partitioner to hash record keys.
+fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().expect("chunks are 4 bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// Offsets to start each partition from at a timestamp, given those the
+/// broker found for it. Partitions without a record at or after it, which
+/// the broker reports as -1 or leaves out, start from their `latest` offset.
+fn offsets_at_time(found: BTreeMap<i32, i64>, latest: BTreeMap<i32, i64>) -> BTreeMap<i32, i64> {
+    latest
+        .into_iter()
+        .map(|(p, end)| {
+            let offset = found.get(&p).copied().filter(|o| *o >= 0);
+            (p, offset.unwrap_or(end))
+        })
+        .collect()
+}
+
+/// Whether the broker answered `code` because the metadata the request was
+/// routed with is out of date.
+fn is_stale_metadata(code: KafkaCode) -> bool {
+    matches!(
+        code,
+        KafkaCode::UnknownTopicOrPartition
+            | KafkaCode::LeaderNotAvailable
+            | KafkaCode::NotLeaderForPartition
+    )
+}
+
+pub(crate) fn is_retryable_kafka_error(err: &kafka::Error) -> bool {
+    let code = match err {
+        kafka::Error::Io(_) | kafka::Error::NoHostReachable => return true,
+        kafka::Error::Kafka(code) => code,
+        kafka::Error::TopicPartitionError { error_code, .. } => error_code,
+        _ => return false,
+    };
+    matches!(
+        code,
+        KafkaCode::UnknownTopicOrPartition
+            | KafkaCode::LeaderNotAvailable
+            | KafkaCode::NotLeaderForPartition
+            | KafkaCode::RequestTimedOut
+            | KafkaCode::BrokerNotAvailable
+            | KafkaCode::ReplicaNotAvailable
+            | KafkaCode::NetworkException
+            | KafkaCode::GroupLoadInProgress
+            | KafkaCode::GroupCoordinatorNotAvailable
+            | KafkaCode::NotCoordinatorForGroup
+            | KafkaCode::NotEnoughReplicas
+            | KafkaCode::NotEnoughReplicasAfterAppend
+            | KafkaCode::RebalanceInProgress
+    )
+}
+
+impl std::fmt::Debug for KafkaBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KafkaBackend")
+            .field("host_addr", &self.host_addr)
+            .field("subscribed", &self.kafka_consumer.is_some())
+            .finish()
+    }
+}
+
+impl NodeStreamBackend for KafkaBackend {
+    fn publish(
+        &mut self,
+        topic: &NodeStreamTopic,
+        record: NodeStreamRecord,
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamBackendError> {
+        self.publish_batch(topic, vec![record])?
+            .pop()
+            .expect("one result per record")
+    }
+
+    fn publish_batch(
+        &mut self,
+        topic: &NodeStreamTopic,
+        records: Vec<NodeStreamRecord>,
+    ) -> Result<Vec<NodeStreamPublishResult>, NodeStreamBackendError> {
+        self.ensure_topic(topic)?;
+        let topic_str = topic.to_raw();
+        let num_partitions = self
+            .producer()?
+            .client()
+            .topics()
+            .partitions(&topic_str)
+            .map_or(0, |p| p.len() as i32);
+        if num_partitions == 0 {
+            return Err(NodeStreamBackendError::TopicNotCreated {
+                topic: topic.clone(),
+            });
+        }
+        let partitions = records
+            .iter()
+            .map(|r| match r.partition {
+                Some(partition) => partition,
+                None => self.partition_for(r.key.as_deref(), num_partitions),
+            })
+            .collect::<Vec<_>>();
+        let records = records
+            .into_iter()
+            .zip(&partitions)
+            // kafka sends empty keys as no key
+            .map(|(r, partition)| {
+                Record::from_key_value(&topic_str, r.key.unwrap_or_default(), r.value)
+                    .with_partition(*partition)
+            })
+            .collect::<Vec<_>>();
+        let confirms = match self.producer()?.send_all(&records) {
+            Ok(confirms) => confirms,
+            Err(err) => {
+                if matches!(err, kafka::Error::Kafka(code) if is_stale_metadata(code)) {
+                    self.metadata_loaded_at = None;
+                }
+                return Err(NodeStreamBackendError::UnableToPublish {
+                    topic: topic.clone(),
+                    err: err.into(),
+                });
+            }
+        };
+
+        // send_all reports broker side failures per partition, and otherwise
+        // the offset of the first record it wrote to each
+        let mut next_offsets = confirms
+            .iter()
+            .flat_map(|c| c.partition_confirms.iter())
+            .map(|p| (p.partition, p.offset))
+            .collect::<BTreeMap<_, _>>();
+        if next_offsets
+            .values()
+            .any(|o| matches!(o, Err(code) if is_stale_metadata(*code)))
+        {
+            self.metadata_loaded_at = None;
+        }
+        Ok(partitions
+            .into_iter()
+            .map(|partition| match next_offsets.get_mut(&partition) {
+                Some(Ok(offset)) => {
+                    *offset += 1;
+                    Ok(NodeStreamDeliveryReceipt {
+                        topic: topic.clone(),
+                        partition,
+                        offset: *offset - 1,
+                    })
+                }
+                Some(Err(code)) => Err(NodeStreamBackendError::UnableToPublish {
+                    topic: topic.clone(),
+                    err: kafka::Error::Kafka(*code).into(),
+                }),
+                None => Err(NodeStreamBackendError::UnconfirmedDelivery {
+                    topic: topic.clone(),
+                    partition,
+                }),
+            })
+            .collect())
+    }
+
+    fn subscribe(
+        &mut self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        start: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let fallback_offset = match start {
+            NodeStreamStartPosition::Earliest => FetchOffset::Earliest,
+            NodeStreamStartPosition::Latest => FetchOffset::Latest,
+            _ => {
+                if !self.has_committed_offsets(topic, group_id)? {
+                    self.commit_position(topic, group_id, start)?;
+                }
+                FetchOffset::Earliest
+            }
+        };
+        self.kafka_consumer = Some(self.create_consumer(topic, group_id, fallback_offset)?);
+        self.subscription = Some(KafkaSubscription {
+            topic: topic.clone(),
+            group_id: group_id.to_string(),
+            fallback_offset,
+        });
+        Ok(())
+    }
+
+    fn seek(
+        &mut self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let group_id = self
+            .subscription
+            .as_ref()
+            .map(|s| s.group_id.clone())
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        self.commit_position(topic, &group_id, position)?;
+        // The kafka consumer keeps its own fetch offsets, start a fresh one
+        self.kafka_consumer =
+            Some(self.create_consumer(topic, &group_id, FetchOffset::Earliest)?);
+        Ok(())
+    }
+
+    /// kafka-rust keeps its fetch offsets private, so these are always where
+    /// the subscription resumed.
+    fn positions(&mut self) -> Result<Vec<NodeStreamPartitionOffset>, NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .clone()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        let topic_str = sub.topic.to_raw();
+        let mut client = self.topic_client(&sub.topic)?;
+        let load_err = |err: kafka::Error| NodeStreamBackendError::UnableToLoadMetadata {
+            topic: Some(sub.topic.clone()),
+            err: err.into(),
+        };
+        let committed = client
+            .fetch_group_topic_offsets(&sub.group_id, &topic_str)
+            .map_err(load_err)?;
+        let earliest = client
+            .fetch_topic_offsets(&topic_str, FetchOffset::Earliest)
+            .map_err(load_err)?;
+        let fallback = match sub.fallback_offset {
+            FetchOffset::Earliest => None,
+            offset => Some(
+                client
+                    .fetch_topic_offsets(&topic_str, offset)
+                    .map_err(load_err)?,
+            ),
+        };
+        Ok(earliest
+            .iter()
+            .map(|e| {
+                let offset = match committed.iter().find(|c| c.partition == e.partition) {
+                    // Offsets dropped by retention are read from the earliest one left
+                    Some(c) if c.offset >= 0 => c.offset.max(e.offset),
+                    // Kafka reports -1 for partitions the group never committed
+                    _ => fallback
+                        .iter()
+                        .flatten()
+                        .find(|f| f.partition == e.partition)
+                        .map_or(e.offset, |f| f.offset),
+                };
+                NodeStreamPartitionOffset {
+                    partition: e.partition,
+                    offset,
+                }
+            })
+            .collect())
+    }
+
+    fn poll(&mut self) -> Result<Vec<NodeStreamFetchedRecord>, NodeStreamBackendError> {
+        let message_sets = self
+            .consumer()?
+            .poll()
+            .map_err(|err| NodeStreamBackendError::UnableToPoll { err: err.into() })?;
+        Ok(message_sets
+            .iter()
+            .flat_map(|w| {
+                let topic = NodeStreamTopic::new(w.topic().to_string());
+                let partition = w.partition();
+                w.messages()
+                    .iter()
+                    .map(|m| NodeStreamFetchedRecord {
+                        topic: topic.clone(),
+                        partition,
+                        offset: m.offset,
+                        value: m.value.to_vec(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    fn commit(
+        &mut self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) -> Result<(), NodeStreamBackendError> {
+        let topic_str = topic.to_raw();
+        let consumer = self.consumer()?;
+        for o in offsets {
+            consumer
+                .consume_message(&topic_str, o.partition, o.offset)
+                .map_err(|err| NodeStreamBackendError::UnableToCommit {
+                    topic: topic.clone(),
+                    err: err.into(),
+                })?;
+        }
+        consumer
+            .commit_consumed()
+            .map_err(|err| NodeStreamBackendError::UnableToCommit {
+                topic: topic.clone(),
+                err: err.into(),
+            })
+    }
+
+    fn topic_metadata(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamBackendError> {
+        let topic_str = topic.to_raw();
+        let client = self.producer()?.client_mut();
+        // Asking for the topic by name would make the broker auto-create it
+        client
+            .load_metadata_all()
+            .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                topic: Some(topic.clone()),
+                err: err.into(),
+            })?;
+        let leaders = match client.topics().partitions(&topic_str) {
+            Some(partitions) => partitions
+                .iter()
+                .map(|p| (p.id(), p.leader().map(|b| b.host().to_string())))
+                .collect::<Vec<_>>(),
+            None => return Ok(None),
+        };
+        let watermarks = client
+            .fetch_topic_offsets(&topic_str, FetchOffset::Latest)
+            .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                topic: Some(topic.clone()),
+                err: err.into(),
+            })?;
+        let partitions = leaders
+            .into_iter()
+            .map(|(partition, leader)| NodeStreamPartitionMetadata {
+                partition,
+                leader,
+                high_watermark: watermarks
+                    .iter()
+                    .find(|w| w.partition == partition)
+                    .map(|w| w.offset)
+                    .unwrap_or_default(),
+            })
+            .collect();
+        Ok(Some(NodeStreamTopicMetadata {
+            topic: topic.clone(),
+            partitions,
+        }))
+    }
+
+    /// Topics are created through the controller, so broker auto-creation is
+    /// not needed. Settings left to `None` use the broker's `num.partitions`
+    /// and `default.replication.factor`. An existing topic is left as is,
+    /// unless it has a different partition count than asked for.
+    fn create_topic(
+        &mut self,
+        topic: &NodeStreamTopic,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamBackendError> {
+        self.validate_topic(topic)?;
+        let result = self.topic_request(topic, |client, topic_str| {
+            client.create_topic(
+                topic_str,
+                config.partitions.unwrap_or(-1),
+                config.replication_factor.unwrap_or(-1),
+            )
+        })?;
+        match result.error_code {
+            0 => Ok(()),
+            kafka_admin::TOPIC_ALREADY_EXISTS => {
+                match (self.topic_metadata(topic)?, config.partitions) {
+                    (Some(existing), Some(wanted))
+                        if existing.partitions.len() as i32 != wanted =>
+                    {
+                        Err(NodeStreamBackendError::Unsupported {
+                            operation: format!(
+                                "resizing kafka topic {} from {} to {} partitions",
+                                topic,
+                                existing.partitions.len(),
+                                wanted
+                            ),
+                        })
+                    }
+                    _ => Ok(()),
+                }
+            }
+            error_code => Err(NodeStreamBackendError::TopicRequestRejected {
+                topic: topic.clone(),
+                error_code,
+                message: result.message,
+            }),
+        }
+    }
+
+    /// Deleting a topic that does not exist succeeds. Fails if the broker has
+    /// `delete.topic.enable` turned off.
+    fn delete_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        self.validate_topic(topic)?;
+        let result =
+            self.topic_request(topic, |client, topic_str| client.delete_topic(topic_str))?;
+        match result.error_code {
+            0 | kafka_admin::UNKNOWN_TOPIC_OR_PARTITION => Ok(()),
+            error_code => Err(NodeStreamBackendError::TopicRequestRejected {
+                topic: topic.clone(),
+                error_code,
+                message: result.message,
+            }),
+        }
+    }
+
+    fn list_topics(&mut self) -> Result<Vec<NodeStreamTopic>, NodeStreamBackendError> {
+        let client = self.producer()?.client_mut();
+        client
+            .load_metadata_all()
+            .map_err(|err| NodeStreamBackendError::UnableToLoadMetadata {
+                topic: None,
+                err: err.into(),
+            })?;
+        Ok(client
+            .topics()
+            .names()
+            .map(|name| NodeStreamTopic::new(name.to_string()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn murmur2_matches_kafka() {
+        // From the tests of Kafka's own murmur2
+        for (key, hash) in [
+            ("21", -973932308),
+            ("foobar", -790332482),
+            ("a-little-bit-long-string", -985981536),
+            ("a-little-bit-longer-string", -1486304829),
+            (
+                "lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            ("abc", 479470107),
+        ] {
+            assert_eq!(murmur2(key.as_bytes()) as i32, hash, "{}", key);
+        }
+    }
+
+    #[test]
+    fn partitions_without_records_after_a_timestamp_start_at_the_end() {
+        let found = BTreeMap::from([(0, 7), (1, -1)]);
+        let latest = BTreeMap::from([(0, 10), (1, 20), (2, 30)]);
+        assert_eq!(
+            offsets_at_time(found, latest),
+            BTreeMap::from([(0, 7), (1, 20), (2, 30)])
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use crate::ack::NodeStreamAckHandle;
+use crate::codec::NodeStreamCodecId;
+use crate::envelope::{self, NodeStreamEnvelopeHeader};
+use crate::producer::PAYLOAD_SIZE_LIMIT;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Records larger than PAYLOAD_SIZE_LIMIT are split into chunk records. A chunk
+// record is an envelope holding a slice of the original record, enveloped
+// and sealed as usual, with CHUNK_HEADER set to bcs(NodeStreamChunkInfo).
+// Consumers concatenate the slices in index order and decode the result as
+// if it had been published as one record.
+
+pub const CHUNK_HEADER: &str = "chunk";
+
+/// Position of a chunk within the record it was split from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStreamChunkInfo {
+    /// Shared by every chunk of the record.
+    pub message_id: [u8; 16],
+    pub index: u32,
+    pub count: u32,
+}
+
+/// Splits the sealed `record` into chunk records sharing `message_id`, each
+/// within `PAYLOAD_SIZE_LIMIT`. Returns `None` if a chunk's own envelope
+/// leaves no room for data, e.g. with a very long `producer_id`.
+pub(crate) fn split(
+    record: &[u8],
+    message_id: [u8; 16],
+    producer_id: &str,
+) -> Option<Vec<Vec<u8>>> {
+    let header = |index, count| {
+        let info = NodeStreamChunkInfo {
+            message_id,
+            index,
+            count,
+        };
+        NodeStreamEnvelopeHeader {
+            schema_version: 0,
+            codec: NodeStreamCodecId::UNSPECIFIED,
+            producer_id: producer_id.to_string(),
+            produced_at_ms: envelope::now_ms(),
+            headers: BTreeMap::from([(
+                CHUNK_HEADER.to_string(),
+                bcs::to_bytes(&info).expect("chunk infos are always serializable"),
+            )]),
+        }
+    };
+    // Headers of every chunk have the same size. The data's length prefix
+    // takes up to 3 more bytes than the empty data's below 2^21 bytes.
+    let overhead = envelope::seal(&header(0, 0), &[]).len() + 3;
+    let data_limit = (PAYLOAD_SIZE_LIMIT as usize)
+        .checked_sub(overhead)
+        .filter(|limit| *limit > 0)?;
+    let count = record.len().div_ceil(data_limit) as u32;
+    Some(
+        record
+            .chunks(data_limit)
+            .enumerate()
+            .map(|(index, data)| envelope::seal(&header(index as u32, count), data))
+            .collect(),
+    )
+}
+
+/// Chunk info of an envelope, if it holds a chunk.
+pub(crate) fn chunk_info(
+    header: &NodeStreamEnvelopeHeader,
+) -> Result<Option<NodeStreamChunkInfo>, String> {
+    let bytes = match header.headers.get(CHUNK_HEADER) {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    let info: NodeStreamChunkInfo = bcs::from_bytes(bytes).map_err(|err| err.to_string())?;
+    if info.index >= info.count {
+        return Err(format!("chunk {} of {}", info.index, info.count));
+    }
+    Ok(Some(info))
+}
+
+/// Limits on the chunks a consumer buffers while waiting for the rest of
+/// their record.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkLimits {
+    pub(crate) timeout: Duration,
+    pub(crate) max_groups: usize,
+    pub(crate) max_record_bytes: usize,
+}
+
+struct ChunkGroup {
+    count: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    bytes: usize,
+    first_seen: Instant,
+    acks: Vec<NodeStreamAckHandle>,
+}
+
+pub(crate) enum ChunkOutcome {
+    /// More chunks of the record are needed.
+    Pending,
+    /// The record is whole again. `acks` covers every chunk of it.
+    Complete {
+        record: Vec<u8>,
+        acks: Vec<NodeStreamAckHandle>,
+    },
+}
+
+/// Chunks of records not fully read yet, grouped by message id.
+#[derive(Default)]
+pub(crate) struct ChunkAssembler {
+    groups: BTreeMap<[u8; 16], ChunkGroup>,
+}
+
+impl ChunkAssembler {
+    /// Buffers a chunk. `ack` is acked once the chunk is no longer needed,
+    /// unless it is handed back as part of a complete record. A chunk which
+    /// cannot be buffered is left untouched, along with its message's other
+    /// chunks, until the caller `discard`s them.
+    pub(crate) fn add(
+        &mut self,
+        info: NodeStreamChunkInfo,
+        data: Vec<u8>,
+        ack: NodeStreamAckHandle,
+        limits: &ChunkLimits,
+    ) -> Result<ChunkOutcome, String> {
+        let group = self
+            .groups
+            .entry(info.message_id)
+            .or_insert_with(|| ChunkGroup {
+                count: info.count,
+                chunks: BTreeMap::new(),
+                bytes: 0,
+                first_seen: Instant::now(),
+                acks: vec![],
+            });
+        if group.count != info.count {
+            return Err(format!(
+                "chunk count {} does not match the {} of earlier chunks",
+                info.count, group.count
+            ));
+        }
+        if group.chunks.contains_key(&info.index) {
+            // Published twice, e.g. by a retried send
+            ack.ack();
+            return Ok(ChunkOutcome::Pending);
+        }
+        if group.bytes + data.len() > limits.max_record_bytes {
+            return Err(format!(
+                "chunked record exceeds {} bytes after {} of {} chunks",
+                limits.max_record_bytes,
+                group.chunks.len() + 1,
+                group.count
+            ));
+        }
+        group.bytes += data.len();
+        group.chunks.insert(info.index, data);
+        group.acks.push(ack);
+
+        if group.chunks.len() < group.count as usize {
+            return Ok(ChunkOutcome::Pending);
+        }
+        let group = self
+            .groups
+            .remove(&info.message_id)
+            .expect("group was just updated");
+        Ok(ChunkOutcome::Complete {
+            record: group.chunks.into_values().flatten().collect(),
+            acks: group.acks,
+        })
+    }
+
+    /// Drops groups which waited longer than the timeout, then the oldest
+    /// groups beyond `max_groups`. Their chunks are acked so they no longer
+    /// hold back commits.
+    pub(crate) fn expire(&mut self, limits: &ChunkLimits) {
+        let expired = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.first_seen.elapsed() >= limits.timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            let group = self.remove(&id);
+            tracing::warn!(
+                "dropping chunked message {} after {} of {} chunks, timed out",
+                hex::encode(id),
+                group.chunks.len(),
+                group.count
+            );
+        }
+        while self.groups.len() > limits.max_groups {
+            let oldest = self
+                .groups
+                .iter()
+                .min_by_key(|(_, group)| group.first_seen)
+                .map(|(id, _)| *id)
+                .expect("there are groups beyond the limit");
+            let group = self.remove(&oldest);
+            tracing::warn!(
+                "dropping chunked message {} after {} of {} chunks, too many incomplete messages",
+                hex::encode(oldest),
+                group.chunks.len(),
+                group.count
+            );
+        }
+    }
+
+    /// Drops the chunks buffered for `message_id`, acking them.
+    pub(crate) fn discard(&mut self, message_id: &[u8; 16]) {
+        if self.groups.contains_key(message_id) {
+            self.remove(message_id);
+        }
+    }
+
+    fn remove(&mut self, id: &[u8; 16]) -> ChunkGroup {
+        let group = self.groups.remove(id).expect("group exists");
+        for ack in &group.acks {
+            ack.ack();
+        }
+        group
+    }
+}
+
+impl std::fmt::Debug for ChunkAssembler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChunkAssembler")
+            .field("groups", &self.groups.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ack::AckTracker;
+    use crate::types::NodeStreamTopic;
+
+    const LIMITS: ChunkLimits = ChunkLimits {
+        timeout: Duration::from_secs(300),
+        max_groups: 4,
+        max_record_bytes: 1024,
+    };
+
+    struct Chunks {
+        tracker: AckTracker,
+        topic: NodeStreamTopic,
+        assembler: ChunkAssembler,
+    }
+
+    impl Chunks {
+        fn new() -> Self {
+            Self {
+                tracker: AckTracker::default(),
+                topic: NodeStreamTopic::new("chunks".to_string()),
+                assembler: ChunkAssembler::default(),
+            }
+        }
+
+        /// Adds chunk `index` of `count` of message `id`, read at `offset`.
+        fn add(
+            &mut self,
+            id: u8,
+            index: u32,
+            count: u32,
+            offset: i64,
+            limits: &ChunkLimits,
+        ) -> Result<ChunkOutcome, String> {
+            let info = NodeStreamChunkInfo {
+                message_id: [id; 16],
+                index,
+                count,
+            };
+            let ack = self.tracker.deliver(&self.topic, 0, offset);
+            self.assembler.add(info, vec![index as u8; 4], ack, limits)
+        }
+
+        fn has_pending(&self) -> bool {
+            self.tracker.has_pending(&self.topic)
+        }
+    }
+
+    fn complete(outcome: Result<ChunkOutcome, String>) -> (Vec<u8>, Vec<NodeStreamAckHandle>) {
+        match outcome {
+            Ok(ChunkOutcome::Complete { record, acks }) => (record, acks),
+            Ok(ChunkOutcome::Pending) => panic!("record is still pending"),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn pending(outcome: Result<ChunkOutcome, String>) {
+        assert!(matches!(outcome, Ok(ChunkOutcome::Pending)));
+    }
+
+    #[test]
+    fn chunks_fit_the_payload_limit_whatever_their_header() {
+        let record = (0..3 * PAYLOAD_SIZE_LIMIT)
+            .map(|b| b as u8)
+            .collect::<Vec<_>>();
+        for producer_id in ["p".to_string(), "p".repeat(100 * 1024)] {
+            let chunks = split(&record, [1; 16], &producer_id).unwrap();
+            let mut data = vec![];
+            for (index, chunk) in chunks.iter().enumerate() {
+                assert!(chunk.len() as u64 <= PAYLOAD_SIZE_LIMIT, "{}", chunk.len());
+                let (header, chunk_data) = envelope::open(chunk).unwrap().unwrap();
+                let info = chunk_info(&header).unwrap().unwrap();
+                assert_eq!(
+                    (info.index, info.count),
+                    (index as u32, chunks.len() as u32)
+                );
+                data.extend(chunk_data);
+            }
+            assert_eq!(data, record);
+        }
+        assert!(split(&record, [1; 16], &"p".repeat(PAYLOAD_SIZE_LIMIT as usize)).is_none());
+    }
+
+    #[test]
+    fn chunks_reassemble_out_of_order_and_interleaved() {
+        let mut chunks = Chunks::new();
+        pending(chunks.add(1, 2, 3, 0, &LIMITS));
+        pending(chunks.add(2, 1, 2, 1, &LIMITS));
+        pending(chunks.add(1, 0, 3, 2, &LIMITS));
+        let (record, acks) = complete(chunks.add(1, 1, 3, 3, &LIMITS));
+        assert_eq!(record, [[0; 4], [1; 4], [2; 4]].concat());
+        assert_eq!(acks.len(), 3);
+
+        let (record, _) = complete(chunks.add(2, 0, 2, 4, &LIMITS));
+        assert_eq!(record, [[0; 4], [1; 4]].concat());
+    }
+
+    #[test]
+    fn duplicate_chunks_are_acked_and_ignored() {
+        let mut chunks = Chunks::new();
+        pending(chunks.add(1, 0, 2, 0, &LIMITS));
+        pending(chunks.add(1, 0, 2, 1, &LIMITS));
+        let (record, acks) = complete(chunks.add(1, 1, 2, 2, &LIMITS));
+        assert_eq!(record, [[0; 4], [1; 4]].concat());
+        for ack in acks {
+            ack.ack();
+        }
+        assert!(!chunks.has_pending());
+
+        // A chunk of a message already reassembled starts a new group
+        pending(chunks.add(1, 1, 2, 3, &LIMITS));
+    }
+
+    #[test]
+    fn expired_chunks_are_dropped_and_acked() {
+        let mut chunks = Chunks::new();
+        pending(chunks.add(1, 0, 2, 0, &LIMITS));
+        chunks.assembler.expire(&LIMITS);
+        assert!(chunks.has_pending());
+
+        let timed_out = ChunkLimits {
+            timeout: Duration::ZERO,
+            ..LIMITS
+        };
+        chunks.assembler.expire(&timed_out);
+        assert!(!chunks.has_pending());
+        // The rest of the message can no longer complete it
+        pending(chunks.add(1, 1, 2, 1, &LIMITS));
+    }
+
+    #[test]
+    fn oldest_chunks_are_dropped_beyond_max_groups() {
+        let mut chunks = Chunks::new();
+        let limits = ChunkLimits {
+            max_groups: 1,
+            ..LIMITS
+        };
+        pending(chunks.add(1, 0, 2, 0, &limits));
+        pending(chunks.add(2, 0, 2, 1, &limits));
+        chunks.assembler.expire(&limits);
+        pending(chunks.add(1, 1, 2, 2, &limits));
+        complete(chunks.add(2, 1, 2, 3, &limits));
+    }
+
+    #[test]
+    fn invalid_chunks_are_kept_until_discarded() {
+        let mut chunks = Chunks::new();
+        let limits = ChunkLimits {
+            max_record_bytes: 6,
+            ..LIMITS
+        };
+        pending(chunks.add(1, 0, 2, 0, &limits));
+        assert!(chunks.add(1, 1, 2, 1, &limits).is_err());
+        assert!(chunks.add(1, 1, 3, 2, &LIMITS).is_err());
+        assert!(chunks.has_pending());
+
+        chunks.assembler.discard(&[1; 16]);
+        // Only the chunk buffered was acked, the others are left to the caller
+        let unacked = chunks.tracker.committable(&chunks.topic);
+        assert_eq!(unacked[0].offset, 0);
+    }
+}
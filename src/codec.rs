@@ -0,0 +1,153 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Identifies the codec of a payload in its envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeStreamCodecId(pub u8);
+
+impl NodeStreamCodecId {
+    pub const UNSPECIFIED: NodeStreamCodecId = NodeStreamCodecId(0);
+    pub const BCS: NodeStreamCodecId = NodeStreamCodecId(1);
+    pub const JSON: NodeStreamCodecId = NodeStreamCodecId(2);
+    pub const CBOR: NodeStreamCodecId = NodeStreamCodecId(3);
+}
+
+/// Serialization format of payloads, independent of how topics are named.
+pub trait NodeStreamCodec {
+    const ID: NodeStreamCodecId;
+    type EncodeError: std::fmt::Debug;
+    type DecodeError: std::fmt::Debug;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::EncodeError>;
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Self::DecodeError>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BcsCodec;
+
+impl NodeStreamCodec for BcsCodec {
+    const ID: NodeStreamCodecId = NodeStreamCodecId::BCS;
+    type EncodeError = bcs::Error;
+    type DecodeError = bcs::Error;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::EncodeError> {
+        bcs::to_bytes(value)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Self::DecodeError> {
+        bcs::from_bytes(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl NodeStreamCodec for JsonCodec {
+    const ID: NodeStreamCodecId = NodeStreamCodecId::JSON;
+    type EncodeError = serde_json::Error;
+    type DecodeError = serde_json::Error;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::EncodeError> {
+        serde_json::to_vec(value)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Self::DecodeError> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+impl NodeStreamCodec for CborCodec {
+    const ID: NodeStreamCodecId = NodeStreamCodecId::CBOR;
+    type EncodeError = ciborium::ser::Error<std::io::Error>;
+    type DecodeError = ciborium::de::Error<std::io::Error>;
+
+    fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::EncodeError> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Self::DecodeError> {
+        ciborium::de::from_reader(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::NodeStreamConsumer;
+    use crate::memory::InMemoryBroker;
+    use crate::producer::NodeStreamProducer;
+    use crate::test_utils::{self, payload, poll_err};
+    use crate::topics::{CborTopic, JsonTopic};
+    use crate::types::{NodeStreamConsumerError, NodeStreamEnvelopeError, NodeStreamPerEpochTopic};
+
+    /// Sends payloads 0..3 through `topic`, returning the data and metadata
+    /// read back with it.
+    fn round_trip<T>(topic: T) -> Vec<(u64, String)>
+    where
+        T: NodeStreamPerEpochTopic<u64, String> + Clone + std::fmt::Debug,
+    {
+        let broker = InMemoryBroker::new();
+        let mut producer = NodeStreamProducer::with_backend(broker.backend());
+        for data in 0..3 {
+            producer.send(0, topic.clone(), &payload(data)).unwrap();
+        }
+        NodeStreamConsumer::with_config(broker.backend(), None, 0, topic, Default::default())
+            .unwrap()
+            .poll()
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.payload.data, m.payload.metdata))
+            .collect()
+    }
+
+    fn expected() -> Vec<(u64, String)> {
+        (0..3).map(|data| (data, payload(data).metdata)).collect()
+    }
+
+    #[test]
+    fn json_topics_round_trip() {
+        assert_eq!(round_trip(JsonTopic::new("codec-test-json-")), expected());
+        let bytes = JsonCodec::encode(&payload(7)).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
+            serde_json::json!({ "metdata": "message 7", "data": 7 })
+        );
+    }
+
+    #[test]
+    fn cbor_topics_round_trip() {
+        assert_eq!(round_trip(CborTopic::new("codec-test-cbor-")), expected());
+        let bytes = CborCodec::encode(&(7u64, "seven")).unwrap();
+        assert_eq!(
+            CborCodec::decode::<(u64, String)>(&bytes).unwrap().1,
+            "seven"
+        );
+        assert!(CborCodec::decode::<(u64, String)>(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn consumers_reject_records_of_another_codec() {
+        let broker = InMemoryBroker::new();
+        let json = JsonTopic::<u64, String>::new("codec-test-");
+        NodeStreamProducer::with_backend(broker.backend())
+            .send(0, json, &payload(0))
+            .unwrap();
+
+        let bcs = test_utils::TestTopic::new("codec-test-");
+        let mut consumer = test_utils::consumer(&broker, None, &bcs, 0, Default::default());
+        match poll_err(&mut consumer, 0) {
+            NodeStreamConsumerError::InvalidEnvelope {
+                err: NodeStreamEnvelopeError::CodecMismatch { expected, found },
+                ..
+            } => assert_eq!(
+                (expected, found),
+                (NodeStreamCodecId::BCS, NodeStreamCodecId::JSON)
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::envelope::NodeStreamEnvelopeHeader;
+use std::io::{Read, Write};
+
+/// Envelope header naming the compression of the payload.
+pub const COMPRESSION_HEADER: &str = "compression";
+
+/// Compression applied to serialized payloads, in each format's framed
+/// encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStreamCompression {
+    Zstd,
+    Lz4,
+    Gzip,
+    Snappy,
+}
+
+impl NodeStreamCompression {
+    const ALL: [NodeStreamCompression; 4] = [Self::Zstd, Self::Lz4, Self::Gzip, Self::Snappy];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+            Self::Gzip => "gzip",
+            Self::Snappy => "snappy",
+        }
+    }
+
+    /// Compresses `payload`, recording the compression in `header`.
+    pub(crate) fn compress(
+        &self,
+        header: &mut NodeStreamEnvelopeHeader,
+        payload: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        let compressed = match self {
+            Self::Zstd => zstd::stream::encode_all(payload, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+            Self::Lz4 => {
+                let mut encoder = lz4::EncoderBuilder::new().build(vec![])?;
+                encoder.write_all(payload)?;
+                let (compressed, result) = encoder.finish();
+                result?;
+                compressed
+            }
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()?
+            }
+            Self::Snappy => {
+                let mut encoder = snap::write::FrameEncoder::new(vec![]);
+                encoder.write_all(payload)?;
+                encoder.into_inner().map_err(|err| err.into_error())?
+            }
+        };
+        header.headers.insert(
+            COMPRESSION_HEADER.to_string(),
+            self.name().as_bytes().to_vec(),
+        );
+        Ok(compressed)
+    }
+
+    /// Decompresses `payload` if `header` says it is compressed, failing
+    /// once the decompressed payload exceeds `limit` bytes.
+    pub(crate) fn decompress(
+        header: &NodeStreamEnvelopeHeader,
+        payload: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<u8>, String> {
+        let name = match header.headers.get(COMPRESSION_HEADER) {
+            Some(name) => name,
+            None => return Ok(payload),
+        };
+        let compression = Self::ALL
+            .into_iter()
+            .find(|c| c.name().as_bytes() == name.as_slice())
+            .ok_or_else(|| format!("unknown compression {}", String::from_utf8_lossy(name)))?;
+        let bytes = payload.as_slice();
+        let reader: Box<dyn Read + '_> = match compression {
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(bytes).map_err(io_err)?),
+            Self::Lz4 => Box::new(lz4::Decoder::new(bytes).map_err(io_err)?),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(bytes)),
+            Self::Snappy => Box::new(snap::read::FrameDecoder::new(bytes)),
+        };
+        let mut decompressed = vec![];
+        reader
+            .take((limit as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(io_err)?;
+        if decompressed.len() > limit {
+            return Err(format!(
+                "{} payload decompresses to more than {} bytes",
+                compression.name(),
+                limit
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+fn io_err(err: std::io::Error) -> String {
+    format!("unable to decompress: {}", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryBroker;
+    use crate::producer::NodeStreamProducer;
+    use crate::test_utils::{header, payload, read_all, TestTopic};
+
+    fn compressible() -> Vec<u8> {
+        b"node stream ".repeat(1000)
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        for compression in NodeStreamCompression::ALL {
+            let mut header = header();
+            let compressed = compression.compress(&mut header, &compressible()).unwrap();
+            assert!(compressed.len() < compressible().len(), "{:?}", compression);
+            assert_eq!(
+                header.headers[COMPRESSION_HEADER],
+                compression.name().as_bytes()
+            );
+            assert_eq!(
+                NodeStreamCompression::decompress(&header, compressed, usize::MAX),
+                Ok(compressible())
+            );
+        }
+        // Payloads without the header are left as they are
+        assert_eq!(
+            NodeStreamCompression::decompress(&header(), b"plain".to_vec(), 0),
+            Ok(b"plain".to_vec())
+        );
+    }
+
+    #[test]
+    fn corrupt_payloads_and_unknown_compressions_fail() {
+        for compression in NodeStreamCompression::ALL {
+            let mut header = header();
+            compression.compress(&mut header, &compressible()).unwrap();
+            let corrupt = b"not compressed at all".to_vec();
+            assert!(
+                NodeStreamCompression::decompress(&header, corrupt, usize::MAX).is_err(),
+                "{:?}",
+                compression
+            );
+        }
+
+        let mut header = header();
+        header
+            .headers
+            .insert(COMPRESSION_HEADER.to_string(), b"brotli".to_vec());
+        assert_eq!(
+            NodeStreamCompression::decompress(&header, compressible(), usize::MAX),
+            Err("unknown compression brotli".to_string())
+        );
+    }
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        for compression in NodeStreamCompression::ALL {
+            let mut header = header();
+            let compressed = compression.compress(&mut header, &compressible()).unwrap();
+            let limit = compressible().len() - 1;
+            assert!(NodeStreamCompression::decompress(&header, compressed, limit).is_err());
+        }
+    }
+
+    #[test]
+    fn producers_count_compressed_payloads() {
+        let broker = InMemoryBroker::new();
+        let topic =
+            TestTopic::new("compression-test-").with_compression(NodeStreamCompression::Zstd);
+        let producer = NodeStreamProducer::with_backend(broker.backend());
+        assert_eq!(producer.metrics().compression_ratio(), 1.0);
+        for data in 0..2 {
+            let mut payload = payload(data);
+            payload.metdata = "node stream ".repeat(1000);
+            // Clones count into the same metrics
+            let mut clone = producer.clone().unwrap();
+            clone.send(0, topic.clone(), &payload).unwrap();
+        }
+
+        let metrics = producer.metrics();
+        assert_eq!(metrics.compressed_records(), 2);
+        assert!(metrics.uncompressed_bytes() > 2 * 12_000);
+        assert!(metrics.compressed_bytes() < metrics.uncompressed_bytes());
+        assert!(metrics.compression_ratio() > 1.0);
+        assert_eq!(read_all(&broker, &topic, 0), vec![0, 1]);
+    }
+}
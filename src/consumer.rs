@@ -1,24 +1,214 @@
+use crate::ack::{AckTracker, NodeStreamAckHandle};
+use crate::admin::list_epoch_topics;
+use crate::backend::{
+    KafkaBackend, NodeStreamBackend, NodeStreamFetchedRecord, NodeStreamPartitionOffset,
+    NodeStreamRecord, NodeStreamStartPosition,
+};
+use crate::chunking::{self, ChunkAssembler, ChunkLimits, ChunkOutcome};
+use crate::codec::NodeStreamCodecId;
+use crate::compression::NodeStreamCompression;
+use crate::encryption::{self, NodeStreamKeyRing};
+use crate::envelope;
+use crate::retry::RetryPolicy;
+use crate::signature::{self, NodeStreamSignatureCheck, NodeStreamSignatureStatus};
+use crate::stream::NodeStreamConsumerStream;
 use crate::types::{
-    NodeStreamConsumerError, NodeStreamMessage, NodeStreamPerEpochTopic, NodeStreamSessionId,
+    NodeStreamBackendError, NodeStreamConsumerError, NodeStreamDeadLetter, NodeStreamEnvelopeError,
+    NodeStreamMessage, NodeStreamPerEpochTopic, NodeStreamSessionId, EPOCH_END_MARKER,
 };
-use kafka::consumer::{Consumer, FetchOffset};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+use sui_types::crypto::AuthorityPublicKeyBytes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EpochRollover {
+    /// Stay on the epoch the consumer was created for.
+    Disabled,
+    /// Move on once an `EPOCH_END_MARKER` was seen on every partition, the
+    /// topic is drained and the next epoch's topic exists.
+    OnEpochEndMarker,
+    /// Move on once the topic is drained and the next epoch's topic holds
+    /// records, whether or not the producer marked the end of the epoch. An
+    /// empty next topic, e.g. one provisioned ahead of time, is not enough.
+    OnNextEpochTopic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStreamCommitMode {
+    /// Offsets are committed by `poll` before it returns the messages, short
+    /// of chunks still waiting for the rest of their message.
+    Auto,
+    /// Messages carry an ack handle and offsets are only committed once the
+    /// messages up to them are acked, giving at-least-once processing.
+    ExplicitAck,
+}
+
+/// What to do with a message whose payload cannot be deserialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStreamPoisonPolicy {
+    /// Fail the poll with the decoding error. Messages decoded before it are
+    /// returned first, and the poll after them fails. The message is neither
+    /// committed nor skipped: every poll fails on it until the consumer
+    /// `seek`s past it.
+    Fail,
+    /// Log the message and carry on as if it had been consumed.
+    Skip,
+    /// Forward the raw message, with the error and its offset, to the topic's
+    /// dead-letter topic as a `NodeStreamDeadLetter`, then carry on. If the
+    /// dead-letter topic cannot be published to, the message is handled like
+    /// with `Fail` and forwarding is tried again by the next poll.
+    DeadLetter,
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeStreamConsumerConfig {
+    pub commit_mode: NodeStreamCommitMode,
+    pub poison_policy: NodeStreamPoisonPolicy,
+    /// Whether to read records written without an envelope, as done by
+    /// producers which predate envelopes or are configured for legacy records.
+    pub accept_legacy_records: bool,
+    /// Reject records without a checksum. Checksums present are always verified.
+    pub require_checksum: bool,
+    pub signature_check: NodeStreamSignatureCheck,
+    /// Signers whose signatures are accepted by `signature_check`.
+    pub trusted_signers: Vec<AuthorityPublicKeyBytes>,
+    /// Keys to decrypt encrypted payloads with, looked up by the key id of each record.
+    pub decryption_keys: Option<NodeStreamKeyRing>,
+    /// Reject records which are not encrypted, legacy records included.
+    /// Otherwise they are read as plaintext, even with `decryption_keys` set.
+    pub require_encryption: bool,
+    /// Largest payload compressed records may decompress to.
+    pub max_decompressed_bytes: usize,
+    /// How long chunks of a chunked message are kept waiting for the rest of
+    /// it. Incomplete messages are then dropped and their chunks committed.
+    pub chunk_timeout: Duration,
+    /// Most chunked messages reassembled at once. The oldest incomplete
+    /// message is dropped to make room for another.
+    pub max_chunk_groups: usize,
+    /// Largest message reassembled from chunks.
+    pub max_chunked_bytes: usize,
+    /// Applied to polls and commits.
+    pub retry: RetryPolicy,
+    /// Where to start if the session has no committed offsets yet. Topics
+    /// reached through epoch rollover are always read from the earliest offset.
+    pub start_position: NodeStreamStartPosition,
+    pub epoch_rollover: EpochRollover,
+    /// Minimum time between two checks for the next epoch's topic.
+    pub rollover_check_interval: Duration,
+}
+
+impl Default for NodeStreamConsumerConfig {
+    fn default() -> Self {
+        Self {
+            commit_mode: NodeStreamCommitMode::Auto,
+            poison_policy: NodeStreamPoisonPolicy::Fail,
+            accept_legacy_records: true,
+            require_checksum: false,
+            signature_check: NodeStreamSignatureCheck::Disabled,
+            trusted_signers: vec![],
+            decryption_keys: None,
+            require_encryption: false,
+            max_decompressed_bytes: 64 * 1024 * 1024,
+            chunk_timeout: Duration::from_secs(300),
+            max_chunk_groups: 16,
+            max_chunked_bytes: 64 * 1024 * 1024,
+            retry: RetryPolicy::default(),
+            start_position: NodeStreamStartPosition::Earliest,
+            epoch_rollover: EpochRollover::Disabled,
+            rollover_check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Progress of an epoch range replay for one epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeStreamEpochProgress {
+    pub epoch: u64,
+    /// Messages yielded so far from this epoch.
+    pub messages: u64,
+    /// Next offset to read per partition, starting from where the session
+    /// resumed on this epoch.
+    pub positions: BTreeMap<i32, i64>,
+    /// High watermark per partition when the replay reached this epoch.
+    pub end_offsets: BTreeMap<i32, i64>,
+    /// Set once the epoch is drained, or if its topic does not exist.
+    pub complete: bool,
+}
+
+impl NodeStreamEpochProgress {
+    fn new(epoch: u64) -> Self {
+        Self {
+            epoch,
+            messages: 0,
+            positions: BTreeMap::new(),
+            end_offsets: BTreeMap::new(),
+            complete: false,
+        }
+    }
+
+    fn caught_up(&self) -> bool {
+        self.end_offsets
+            .iter()
+            .all(|(p, end)| self.positions.get(p).copied().unwrap_or_default() >= *end)
+    }
+}
+
+#[derive(Debug)]
+struct EpochReplay {
+    last_epoch: u64,
+    progress: Vec<NodeStreamEpochProgress>,
+    finished: bool,
+}
+
+/// A fetched record after chunk reassembly.
+enum Reassembled {
+    /// A whole message, with the acks of every record it was read from.
+    Ready(NodeStreamFetchedRecord, Vec<NodeStreamAckHandle>),
+    /// A chunk buffered until the rest of its message comes in.
+    Pending,
+    /// A chunk which cannot be buffered. `message_id` is set if the chunks
+    /// buffered for its message are to be dropped along with it.
+    Invalid {
+        record: NodeStreamFetchedRecord,
+        ack: NodeStreamAckHandle,
+        err: NodeStreamConsumerError,
+        message_id: Option<[u8; 16]>,
+    },
+}
 
 #[derive(Debug)]
 pub struct NodeStreamConsumer<
     TopicType: NodeStreamPerEpochTopic<DataType, MetadataType>,
     DataType,
     MetadataType,
+    Backend: NodeStreamBackend = KafkaBackend,
 > where
     DataType: std::fmt::Debug,
     MetadataType: std::fmt::Debug,
 {
-    host_addr: SocketAddr,
     session: NodeStreamSessionId,
-    kafka_consumer: Consumer,
+    backend: Backend,
     topic: TopicType,
     epoch: u64,
+    config: NodeStreamConsumerConfig,
+    /// Partitions of the current epoch's topic an `EPOCH_END_MARKER` was
+    /// read from.
+    epoch_end_partitions: BTreeSet<i32>,
+    last_rollover_check: Option<Instant>,
+    replay: Option<EpochReplay>,
+    acks: AckTracker,
+    chunks: ChunkAssembler,
+    /// Records fetched but not handed out yet, because the poll which
+    /// fetched them stopped at a message the poison policy did not get past.
+    undelivered: VecDeque<NodeStreamFetchedRecord>,
+    /// That message, already reassembled, to be decoded again first.
+    poisoned: Option<(NodeStreamFetchedRecord, Vec<NodeStreamAckHandle>)>,
+    /// Messages read by a stream which it had not yielded when it was turned
+    /// back into the consumer, to be returned by the next poll.
+    unread: VecDeque<NodeStreamMessage<DataType, MetadataType>>,
     phantom: PhantomData<DataType>,
     phantom2: PhantomData<MetadataType>,
 }
@@ -37,31 +227,158 @@ impl<
         epoch: u64,
         topic: T,
     ) -> Result<Self, NodeStreamConsumerError> {
-        let topic_str = topic.topic_for_epoch(epoch).to_raw();
-        let session = session_id.unwrap_or(NodeStreamSessionId::new());
-        let kafka_consumer = Consumer::from_hosts(vec![host_addr.to_string()])
-            .with_group(session.to_group_id())
-            .with_topic(topic_str)
-            .with_fallback_offset(FetchOffset::Earliest)
-            .create()
+        Self::with_backend(KafkaBackend::new(host_addr), session_id, epoch, topic)
+    }
+
+    pub fn new_epoch_range(
+        host_addr: SocketAddr,
+        session_id: Option<NodeStreamSessionId>,
+        epochs: RangeInclusive<u64>,
+        topic: T,
+    ) -> Result<Self, NodeStreamConsumerError> {
+        Self::with_epoch_range(
+            KafkaBackend::new(host_addr),
+            session_id,
+            epochs,
+            topic,
+            NodeStreamConsumerConfig::default(),
+        )
+    }
+
+    pub fn new_latest_epoch(
+        host_addr: SocketAddr,
+        session_id: Option<NodeStreamSessionId>,
+        topic: T,
+    ) -> Result<Self, NodeStreamConsumerError> {
+        Self::with_latest_epoch(
+            KafkaBackend::new(host_addr),
+            session_id,
+            topic,
+            NodeStreamConsumerConfig::default(),
+        )
+    }
+}
+
+impl<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+        B: NodeStreamBackend,
+    > NodeStreamConsumer<T, D, M, B>
+{
+    pub fn with_backend(
+        backend: B,
+        session_id: Option<NodeStreamSessionId>,
+        epoch: u64,
+        topic: T,
+    ) -> Result<Self, NodeStreamConsumerError> {
+        Self::with_config(
+            backend,
+            session_id,
+            epoch,
+            topic,
+            NodeStreamConsumerConfig::default(),
+        )
+    }
+
+    pub fn with_config(
+        mut backend: B,
+        session_id: Option<NodeStreamSessionId>,
+        epoch: u64,
+        topic: T,
+        config: NodeStreamConsumerConfig,
+    ) -> Result<Self, NodeStreamConsumerError> {
+        let session = session_id.unwrap_or_default();
+        backend
+            .subscribe(
+                &topic.topic_for_epoch(epoch),
+                &session.to_group_id(),
+                &config.start_position,
+            )
             .map_err(|err| NodeStreamConsumerError::UnableToCreateConsumer { err })?;
         Ok(Self {
-            host_addr,
             session,
-            kafka_consumer,
+            backend,
             topic,
             epoch,
+            config,
+            epoch_end_partitions: BTreeSet::new(),
+            last_rollover_check: None,
+            replay: None,
+            acks: AckTracker::default(),
+            chunks: ChunkAssembler::default(),
+            undelivered: VecDeque::new(),
+            poisoned: None,
+            unread: VecDeque::new(),
             phantom: PhantomData,
             phantom2: PhantomData,
         })
     }
 
-    /// This will restart from the beginning of the stream.
+    /// Consumes the highest epoch which has a topic. Fails with
+    /// `NoEpochAvailable` if no epoch of the family has one yet.
+    pub fn with_latest_epoch(
+        mut backend: B,
+        session_id: Option<NodeStreamSessionId>,
+        topic: T,
+        config: NodeStreamConsumerConfig,
+    ) -> Result<Self, NodeStreamConsumerError> {
+        let epoch = list_epoch_topics(&mut backend, &topic)
+            .map_err(|err| NodeStreamConsumerError::UnableToDiscoverEpochs { err })?
+            .last()
+            .map(|(epoch, _)| *epoch)
+            .ok_or(NodeStreamConsumerError::NoEpochAvailable)?;
+        Self::with_config(backend, session_id, epoch, topic, config)
+    }
+
+    /// Replays `epochs` in order as one stream, each epoch from the earliest
+    /// offset unless `session_id` already committed offsets for it. An epoch
+    /// is done once the consumer reached the high watermark its topic had
+    /// when the replay got to it. Epochs without a topic are skipped.
+    /// `config.start_position` and `config.epoch_rollover` do not apply.
+    pub fn with_epoch_range(
+        backend: B,
+        session_id: Option<NodeStreamSessionId>,
+        epochs: RangeInclusive<u64>,
+        topic: T,
+        config: NodeStreamConsumerConfig,
+    ) -> Result<Self, NodeStreamConsumerError> {
+        let mut consumer = Self {
+            session: session_id.unwrap_or_default(),
+            backend,
+            topic,
+            epoch: *epochs.start(),
+            config,
+            epoch_end_partitions: BTreeSet::new(),
+            last_rollover_check: None,
+            replay: Some(EpochReplay {
+                last_epoch: *epochs.end(),
+                progress: vec![],
+                finished: epochs.is_empty(),
+            }),
+            acks: AckTracker::default(),
+            chunks: ChunkAssembler::default(),
+            undelivered: VecDeque::new(),
+            poisoned: None,
+            unread: VecDeque::new(),
+            phantom: PhantomData,
+            phantom2: PhantomData,
+        };
+        if !epochs.is_empty() {
+            consumer
+                .enter_replay_epoch(*epochs.start())
+                .map_err(|err| NodeStreamConsumerError::UnableToCreateConsumer { err })?;
+        }
+        Ok(consumer)
+    }
+
+    /// This will restart from the beginning of the stream. A consumer replaying
+    /// an epoch range keeps reading from its current epoch only.
     pub fn reset_with_session(
         self,
         session_id: Option<NodeStreamSessionId>,
     ) -> Result<Self, NodeStreamConsumerError> {
-        let session_id: NodeStreamSessionId = session_id.unwrap_or(NodeStreamSessionId::new());
+        let session_id: NodeStreamSessionId = session_id.unwrap_or_default();
 
         if self.session == session_id {
             return Err(NodeStreamConsumerError::DuplicateSession {
@@ -69,58 +386,881 @@ impl<
                 new: session_id,
             });
         }
-        Self::new(self.host_addr, Some(self.session), self.epoch, self.topic)
+        Self::with_config(
+            self.backend,
+            Some(session_id),
+            self.epoch,
+            self.topic,
+            self.config,
+        )
     }
 
     pub fn poll(&mut self) -> Result<Vec<NodeStreamMessage<D, M>>, NodeStreamConsumerError> {
-        let r = self
-            .kafka_consumer
-            .poll()
-            .map_err(|err| NodeStreamConsumerError::UnableToPollMessage {
-                topic: self.topic.topic_for_epoch(self.epoch),
+        if self.is_finished() {
+            return Ok(vec![]);
+        }
+        if !self.unread.is_empty() {
+            let auto_commit = self.config.commit_mode == NodeStreamCommitMode::Auto;
+            if auto_commit {
+                self.unread.iter().for_each(|m| m.ack());
+                self.commit_acked()?;
+            }
+            return Ok(self
+                .unread
+                .drain(..)
+                .map(|m| {
+                    if auto_commit {
+                        NodeStreamMessage { ack: None, ..m }
+                    } else {
+                        m
+                    }
+                })
+                .collect());
+        }
+        self.chunks.expire(&self.chunk_limits());
+        self.commit_acked()?;
+        let topic = self.topic.topic_for_epoch(self.epoch);
+        let mut records = std::mem::take(&mut self.undelivered);
+        let mut poisoned = self.poisoned.take();
+        if records.is_empty() && poisoned.is_none() {
+            let backend = &mut self.backend;
+            records = self
+                .config
+                .retry
+                .run(NodeStreamBackendError::is_retryable, || backend.poll())
+                .map_err(|err| NodeStreamConsumerError::UnableToPollMessage {
+                    topic: topic.clone(),
+                    err,
+                })?
+                .into();
+            if records.is_empty() {
+                match &self.replay {
+                    // An empty fetch alone does not mean the epoch is drained
+                    Some(replay) => {
+                        if replay.progress.last().is_none_or(|p| p.caught_up()) {
+                            self.finish_replay_epoch()?;
+                        }
+                    }
+                    None => self.maybe_roll_over()?,
+                }
+                return Ok(vec![]);
+            }
+        }
+
+        let mut last_offsets = BTreeMap::new();
+        let mut r = Vec::with_capacity(records.len());
+        // Set if a message could not be got past, which ends the poll there
+        let failure = loop {
+            let (m, acks) = match poisoned.take() {
+                Some(poisoned) => poisoned,
+                None => {
+                    let m = match records.pop_front() {
+                        Some(m) => m,
+                        None => break None,
+                    };
+                    last_offsets.insert(m.partition, m.offset);
+                    if m.value == EPOCH_END_MARKER {
+                        self.epoch_end_partitions.insert(m.partition);
+                        self.acks.skip(&topic, m.partition, m.offset);
+                        continue;
+                    }
+                    match self.reassemble(m) {
+                        Reassembled::Ready(m, acks) => (m, acks),
+                        Reassembled::Pending => continue,
+                        Reassembled::Invalid {
+                            record,
+                            ack,
+                            err,
+                            message_id,
+                        } => match self.handle_poison_message(&record, err) {
+                            Ok(()) => {
+                                ack.ack();
+                                if let Some(message_id) = message_id {
+                                    self.chunks.discard(&message_id);
+                                }
+                                continue;
+                            }
+                            Err(err) => {
+                                records.push_front(record);
+                                break Some(err);
+                            }
+                        },
+                    }
+                }
+            };
+            match self.decode_record(&m) {
+                Ok(message) => match self.config.commit_mode {
+                    NodeStreamCommitMode::Auto => {
+                        acks.iter().for_each(|ack| ack.ack());
+                        r.push(message);
+                    }
+                    NodeStreamCommitMode::ExplicitAck => r.push(NodeStreamMessage {
+                        ack: NodeStreamAckHandle::merge(acks),
+                        ..message
+                    }),
+                },
+                Err(err) => match self.handle_poison_message(&m, err) {
+                    Ok(()) => acks.iter().for_each(|ack| ack.ack()),
+                    Err(err) => {
+                        self.poisoned = Some((m, acks));
+                        break Some(err);
+                    }
+                },
+            }
+        };
+        self.undelivered = records;
+        self.chunks.expire(&self.chunk_limits());
+        if self.config.commit_mode == NodeStreamCommitMode::Auto {
+            self.commit_acked()?;
+        }
+
+        let offsets = last_offsets
+            .into_iter()
+            .map(|(partition, offset)| NodeStreamPartitionOffset { partition, offset })
+            .collect::<Vec<_>>();
+        if let Some(progress) = self.replay.as_mut().and_then(|r| r.progress.last_mut()) {
+            progress.messages += r.len() as u64;
+            for o in &offsets {
+                progress.positions.insert(o.partition, o.offset + 1);
+            }
+            if progress.caught_up() {
+                self.finish_replay_epoch()?;
+            }
+        }
+        match failure {
+            Some(err) if r.is_empty() => Err(err),
+            _ => Ok(r),
+        }
+    }
+
+    /// Hands out the ack handles of `record` unless it is a chunk. Chunks are
+    /// buffered until the last one of their message comes in, which is then
+    /// handed out as a record holding the whole message.
+    fn reassemble(&mut self, record: NodeStreamFetchedRecord) -> Reassembled {
+        let topic = self.topic.topic_for_epoch(self.epoch);
+        let ack = self.acks.deliver(&topic, record.partition, record.offset);
+        let invalid =
+            |record: &NodeStreamFetchedRecord, err: String| NodeStreamConsumerError::InvalidChunk {
+                topic: record.topic.clone(),
+                offset: record.offset,
                 err,
-            })?
-            .iter()
-            .map(|w| {
-                let vals = w
-                    .messages()
-                    .iter()
-                    .map(|m| {
-                        self.topic
-                            .payload_from_bytes(m.value)
-                            .map_err(|err| NodeStreamConsumerError::PayloadDeserializeError {
-                                topic: self.topic.topic_for_epoch(self.epoch),
-                                err: format!("{:?}", err),
-                            })
-                            .map(|p| NodeStreamMessage {
-                                payload: p,
-                                message_offset: m.offset,
-                            })
-                    })
-                    .collect::<Result<Vec<_>, _>>();
-                self.kafka_consumer.consume_messageset(w).map_err(|err| {
-                    NodeStreamConsumerError::UnableToMarkMessageConsumed {
-                        topic: self.topic.topic_for_epoch(self.epoch),
-                        err,
+            };
+        let (info, data) = match envelope::open(&record.value) {
+            Ok(Some((header, data))) => match chunking::chunk_info(&header) {
+                Ok(Some(info)) => (info, data),
+                Ok(None) => return Reassembled::Ready(record, vec![ack]),
+                Err(err) => {
+                    return Reassembled::Invalid {
+                        err: invalid(&record, err),
+                        record,
+                        ack,
+                        message_id: None,
                     }
-                })?;
-                vals
+                }
+            },
+            // Left to decode_record to report
+            _ => return Reassembled::Ready(record, vec![ack]),
+        };
+        match self
+            .chunks
+            .add(info, data, ack.clone(), &self.chunk_limits())
+        {
+            Ok(ChunkOutcome::Pending) => Reassembled::Pending,
+            Ok(ChunkOutcome::Complete {
+                record: value,
+                acks,
+            }) => Reassembled::Ready(NodeStreamFetchedRecord { value, ..record }, acks),
+            Err(err) => Reassembled::Invalid {
+                err: invalid(&record, err),
+                record,
+                ack,
+                message_id: Some(info.message_id),
+            },
+        }
+    }
+
+    fn chunk_limits(&self) -> ChunkLimits {
+        ChunkLimits {
+            timeout: self.config.chunk_timeout,
+            max_groups: self.config.max_chunk_groups,
+            max_record_bytes: self.config.max_chunked_bytes,
+        }
+    }
+
+    /// Strips and validates the envelope of `record`, then deserializes its payload.
+    fn decode_record(
+        &self,
+        record: &NodeStreamFetchedRecord,
+    ) -> Result<NodeStreamMessage<D, M>, NodeStreamConsumerError> {
+        let invalid = |err| NodeStreamConsumerError::InvalidEnvelope {
+            topic: record.topic.clone(),
+            offset: record.offset,
+            err,
+        };
+        let (header, bytes) = match envelope::open(&record.value) {
+            Ok(Some((header, bytes))) => (Some(header), bytes),
+            Ok(None) if self.config.accept_legacy_records => (None, record.value.clone()),
+            Ok(None) => return Err(invalid(NodeStreamEnvelopeError::LegacyRecord)),
+            // A legacy payload starting with ENVELOPE_MAGIC by chance
+            Err(_)
+                if self.config.accept_legacy_records
+                    && self.topic.payload_from_bytes(&record.value).is_ok() =>
+            {
+                (None, record.value.clone())
+            }
+            Err(err) => return Err(invalid(err)),
+        };
+        let checksummed = match &header {
+            Some(header) => header.verify_checksums(&bytes).map_err(|algorithm| {
+                NodeStreamConsumerError::ChecksumMismatch {
+                    topic: record.topic.clone(),
+                    offset: record.offset,
+                    algorithm,
+                }
+            })?,
+            None => false,
+        };
+        if self.config.require_checksum && !checksummed {
+            return Err(invalid(NodeStreamEnvelopeError::MissingChecksum));
+        }
+        if self.config.require_encryption && !header.as_ref().is_some_and(encryption::is_encrypted)
+        {
+            return Err(invalid(NodeStreamEnvelopeError::NotEncrypted));
+        }
+        if let Some(header) = &header {
+            let expected = self.topic.codec_id();
+            if header.codec != expected
+                && header.codec != NodeStreamCodecId::UNSPECIFIED
+                && expected != NodeStreamCodecId::UNSPECIFIED
+            {
+                return Err(invalid(NodeStreamEnvelopeError::CodecMismatch {
+                    expected,
+                    found: header.codec,
+                }));
+            }
+            if header.schema_version > self.topic.schema_version() {
+                return Err(invalid(NodeStreamEnvelopeError::UnsupportedSchemaVersion {
+                    supported: self.topic.schema_version(),
+                    found: header.schema_version,
+                }));
+            }
+        }
+        let signature = match self.config.signature_check {
+            NodeStreamSignatureCheck::Disabled => NodeStreamSignatureStatus::NotChecked,
+            check => {
+                let status = signature::verify(
+                    header.as_ref(),
+                    &record.topic,
+                    &bytes,
+                    &self.config.trusted_signers,
+                );
+                let verified = matches!(status, NodeStreamSignatureStatus::Verified { .. });
+                if check == NodeStreamSignatureCheck::Require && !verified {
+                    return Err(NodeStreamConsumerError::InvalidSignature {
+                        topic: record.topic.clone(),
+                        offset: record.offset,
+                        status,
+                    });
+                }
+                status
+            }
+        };
+        let bytes = match &header {
+            Some(header) => NodeStreamKeyRing::decrypt(
+                self.config.decryption_keys.as_ref(),
+                header,
+                &record.topic,
+                bytes,
+            )
+            .map_err(|err| NodeStreamConsumerError::UnableToDecrypt {
+                topic: record.topic.clone(),
+                offset: record.offset,
+                err,
+            })?,
+            None => bytes,
+        };
+        let bytes = match &header {
+            Some(header) => {
+                NodeStreamCompression::decompress(header, bytes, self.config.max_decompressed_bytes)
+                    .map_err(|err| NodeStreamConsumerError::UnableToDecompress {
+                        topic: record.topic.clone(),
+                        offset: record.offset,
+                        err,
+                    })?
+            }
+            None => bytes,
+        };
+        let payload = self.topic.payload_from_bytes(&bytes).map_err(|err| {
+            NodeStreamConsumerError::PayloadDeserializeError {
+                topic: record.topic.clone(),
+                err: format!("{:?}", err),
+            }
+        })?;
+        Ok(NodeStreamMessage {
+            epoch: self.epoch,
+            message_offset: record.offset,
+            payload,
+            envelope: header,
+            signature,
+            ack: None,
+        })
+    }
+
+    /// Applies the poison policy to `record`, which failed to decode with `err`.
+    /// Returns an error if the consumer cannot get past the record.
+    fn handle_poison_message(
+        &mut self,
+        record: &NodeStreamFetchedRecord,
+        err: NodeStreamConsumerError,
+    ) -> Result<(), NodeStreamConsumerError> {
+        match self.config.poison_policy {
+            NodeStreamPoisonPolicy::Fail => Err(err),
+            NodeStreamPoisonPolicy::Skip => {
+                tracing::warn!(
+                    "skipping message at offset {} of topic {} partition {}, err: {}",
+                    record.offset,
+                    record.topic,
+                    record.partition,
+                    err
+                );
+                Ok(())
+            }
+            NodeStreamPoisonPolicy::DeadLetter => {
+                let dead_letter_topic = record.topic.dead_letter();
+                let (topic, offset) = (record.topic.clone(), record.offset);
+                let dead_letter = NodeStreamDeadLetter {
+                    topic: record.topic.clone(),
+                    epoch: self.epoch,
+                    partition: record.partition,
+                    offset: record.offset,
+                    error: err.to_string(),
+                    payload: record.value.clone(),
+                };
+                let value = dead_letter
+                    .to_bytes()
+                    .expect("dead letters are always serializable");
+                self.backend
+                    .publish(
+                        &dead_letter_topic,
+                        NodeStreamRecord {
+                            key: None,
+                            value,
+                            partition: None,
+                        },
+                    )
+                    .map(|_| ())
+                    .map_err(|err| NodeStreamConsumerError::UnableToDeadLetter {
+                        topic,
+                        offset,
+                        err,
+                    })
+            }
+        }
+    }
+
+    /// Commits the offsets made committable by acks since the last commit.
+    /// `poll` does this itself, so this is only needed to commit acks without
+    /// polling again, e.g. before shutting down.
+    pub fn commit_acked(&mut self) -> Result<(), NodeStreamConsumerError> {
+        let topic = self.topic.topic_for_epoch(self.epoch);
+        let offsets = self.acks.committable(&topic);
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let backend = &mut self.backend;
+        self.config
+            .retry
+            .run(NodeStreamBackendError::is_retryable, || {
+                backend.commit(&topic, &offsets)
             })
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .flatten()
-            .collect();
+            .map_err(
+                |err| NodeStreamConsumerError::UnableToCommitMessageConsumed {
+                    topic: topic.clone(),
+                    err,
+                },
+            )?;
+        self.acks.mark_committed(&topic, &offsets);
+        Ok(())
+    }
 
-        self.kafka_consumer.commit_consumed().map_err(|err| {
-            NodeStreamConsumerError::UnableToCommitMessageConsumed {
-                topic: self.topic.topic_for_epoch(self.epoch),
+    /// Whether the current epoch can be left: messages still waiting for an
+    /// ack have to be committed from this epoch's subscription.
+    fn settled(&mut self) -> Result<bool, NodeStreamConsumerError> {
+        if self
+            .acks
+            .has_pending(&self.topic.topic_for_epoch(self.epoch))
+        {
+            return Ok(false);
+        }
+        self.commit_acked()?;
+        Ok(true)
+    }
+
+    /// Marks the current replay epoch complete and moves on to the next one.
+    fn finish_replay_epoch(&mut self) -> Result<(), NodeStreamConsumerError> {
+        if !self.settled()? {
+            return Ok(());
+        }
+        let replay = match self.replay.as_mut() {
+            Some(replay) => replay,
+            None => return Ok(()),
+        };
+        if let Some(progress) = replay.progress.last_mut() {
+            progress.complete = true;
+        }
+        if self.epoch >= replay.last_epoch {
+            replay.finished = true;
+            return Ok(());
+        }
+        self.enter_replay_epoch(self.epoch + 1).map_err(|err| {
+            NodeStreamConsumerError::UnableToRollOverEpoch {
+                epoch: self.epoch,
+                err,
+            }
+        })
+    }
+
+    /// Subscribes to the first epoch from `epoch` on which has a topic.
+    fn enter_replay_epoch(&mut self, mut epoch: u64) -> Result<(), NodeStreamBackendError> {
+        let replay = self.replay.as_mut().expect("only used when replaying");
+        loop {
+            self.epoch = epoch;
+            let topic = self.topic.topic_for_epoch(epoch);
+            let mut progress = NodeStreamEpochProgress::new(epoch);
+            match self.backend.topic_metadata(&topic)? {
+                Some(metadata) => {
+                    progress.end_offsets = metadata
+                        .partitions
+                        .iter()
+                        .map(|p| (p.partition, p.high_watermark))
+                        .collect();
+                    self.backend.subscribe(
+                        &topic,
+                        &self.session.to_group_id(),
+                        &NodeStreamStartPosition::Earliest,
+                    )?;
+                    // A session restarted on an epoch it already read resumes at its end
+                    progress.positions = self
+                        .backend
+                        .positions()?
+                        .into_iter()
+                        .map(|o| (o.partition, o.offset))
+                        .collect();
+                    replay.progress.push(progress);
+                    return Ok(());
+                }
+                None => {
+                    progress.complete = true;
+                    replay.progress.push(progress);
+                    if epoch >= replay.last_epoch {
+                        replay.finished = true;
+                        return Ok(());
+                    }
+                    epoch += 1;
+                }
+            }
+        }
+    }
+
+    /// Called once a poll came back empty, meaning the current topic is drained.
+    fn maybe_roll_over(&mut self) -> Result<(), NodeStreamConsumerError> {
+        match self.config.epoch_rollover {
+            EpochRollover::Disabled => return Ok(()),
+            EpochRollover::OnEpochEndMarker if self.epoch_end_partitions.is_empty() => {
+                return Ok(())
+            }
+            _ => {}
+        }
+        if self
+            .last_rollover_check
+            .is_some_and(|t| t.elapsed() < self.config.rollover_check_interval)
+            || !self.settled()?
+        {
+            return Ok(());
+        }
+        self.last_rollover_check = Some(Instant::now());
+
+        let epoch = self.epoch;
+        let rollover_err = |err| NodeStreamConsumerError::UnableToRollOverEpoch { epoch, err };
+        let epoch_ended = self.epoch_ended().map_err(rollover_err)?;
+        if self.config.epoch_rollover == EpochRollover::OnEpochEndMarker && !epoch_ended {
+            return Ok(());
+        }
+        let next_topic = self.topic.topic_for_epoch(self.epoch + 1);
+        let next = self
+            .backend
+            .topic_metadata(&next_topic)
+            .map_err(rollover_err)?;
+        let ready = next.is_some_and(|next| {
+            epoch_ended || next.partitions.iter().any(|p| p.high_watermark > 0)
+        });
+        if !ready {
+            return Ok(());
+        }
+
+        self.backend
+            .subscribe(
+                &next_topic,
+                &self.session.to_group_id(),
+                &NodeStreamStartPosition::Earliest,
+            )
+            .map_err(rollover_err)?;
+        self.epoch += 1;
+        self.epoch_end_partitions.clear();
+        self.last_rollover_check = None;
+        Ok(())
+    }
+
+    /// Whether an `EPOCH_END_MARKER` was read from every partition of the
+    /// current epoch's topic. Partitions are marked one by one, so a marker
+    /// on one of them says nothing about records left on the others.
+    fn epoch_ended(&mut self) -> Result<bool, NodeStreamBackendError> {
+        if self.epoch_end_partitions.is_empty() {
+            return Ok(false);
+        }
+        let topic = self.topic.topic_for_epoch(self.epoch);
+        Ok(self
+            .backend
+            .topic_metadata(&topic)?
+            .is_some_and(|metadata| {
+                metadata
+                    .partitions
+                    .iter()
+                    .all(|p| self.epoch_end_partitions.contains(&p.partition))
+            }))
+    }
+
+    /// Moves the consumer to `position` on the current epoch's topic and
+    /// commits it for the session.
+    pub fn seek(
+        &mut self,
+        position: NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamConsumerError> {
+        let topic = self.topic.topic_for_epoch(self.epoch);
+        self.backend.seek(&topic, &position).map_err(|err| {
+            NodeStreamConsumerError::UnableToSeek {
+                topic: topic.clone(),
                 err,
             }
         })?;
-        Ok(r)
+        self.acks.reset_positions(&topic);
+        self.undelivered.clear();
+        self.poisoned = None;
+        self.unread.clear();
+        Ok(())
     }
 
     pub fn session_id(&self) -> NodeStreamSessionId {
         self.session
     }
+
+    /// Epoch whose topic the consumer is currently reading.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the epochs of the consumed topic family which have a topic,
+    /// in ascending order.
+    pub fn available_epochs(&mut self) -> Result<Vec<u64>, NodeStreamConsumerError> {
+        Ok(list_epoch_topics(&mut self.backend, &self.topic)
+            .map_err(|err| NodeStreamConsumerError::UnableToDiscoverEpochs { err })?
+            .into_iter()
+            .map(|(epoch, _)| epoch)
+            .collect())
+    }
+
+    /// Returns the highest epoch of the consumed topic family which has a topic.
+    pub fn latest_epoch(&mut self) -> Result<Option<u64>, NodeStreamConsumerError> {
+        Ok(self.available_epochs()?.last().copied())
+    }
+
+    /// Per epoch progress of an epoch range replay, in epoch order. Empty
+    /// for consumers not created with an epoch range.
+    pub fn epoch_progress(&self) -> &[NodeStreamEpochProgress] {
+        self.replay
+            .as_ref()
+            .map(|r| r.progress.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// True once an epoch range replay went through its last epoch.
+    pub fn is_finished(&self) -> bool {
+        self.replay.as_ref().is_some_and(|r| r.finished)
+    }
+
+    pub(crate) fn retry_policy(&self) -> &RetryPolicy {
+        &self.config.retry
+    }
+
+    /// Switches a consumer in auto commit mode to explicit acks while it is
+    /// polled by a stream, which acks messages as it yields them. Returns
+    /// whether it was in auto commit mode.
+    pub(crate) fn start_streaming(&mut self) -> bool {
+        let auto_commit = self.config.commit_mode == NodeStreamCommitMode::Auto;
+        self.config.commit_mode = NodeStreamCommitMode::ExplicitAck;
+        auto_commit
+    }
+
+    /// Commits what was acked while streaming and switches back to auto
+    /// commit mode if `start_streaming` left it.
+    pub(crate) fn stop_streaming(&mut self, auto_commit: bool) {
+        if let Err(err) = self.commit_acked() {
+            tracing::warn!("unable to commit messages read by stream, err: {}", err);
+        }
+        if auto_commit {
+            self.config.commit_mode = NodeStreamCommitMode::Auto;
+        }
+    }
+
+    /// Puts messages a stream read but did not yield in front of those the
+    /// next poll returns.
+    pub(crate) fn unread(
+        &mut self,
+        messages: impl DoubleEndedIterator<Item = NodeStreamMessage<D, M>>,
+    ) {
+        for message in messages.rev() {
+            self.unread.push_front(message);
+        }
+    }
+}
+
+impl<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug + Send + 'static,
+        D: std::fmt::Debug + Send + 'static,
+        M: std::fmt::Debug + Send + 'static,
+        B: NodeStreamBackend + 'static,
+    > NodeStreamConsumer<T, D, M, B>
+{
+    /// Turns the consumer into an async stream yielding messages one at a
+    /// time. Must be called from within a tokio runtime.
+    pub fn into_stream(self) -> NodeStreamConsumerStream<T, D, M, B> {
+        NodeStreamConsumerStream::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryBroker;
+    use crate::producer::NodeStreamProducer;
+    use crate::test_utils::{
+        self, payload, publish, publish_raw, PartitionedBackend, TestConsumer, TestTopic,
+    };
+
+    fn topic() -> TestTopic {
+        TestTopic::new("consumer-test-")
+    }
+
+    /// Polls until `consumer` is finished, returning the data of the messages.
+    fn drain_replay(consumer: &mut TestConsumer) -> Vec<u64> {
+        let mut data = vec![];
+        for _ in 0..20 {
+            if consumer.is_finished() {
+                return data;
+            }
+            data.extend(consumer.poll().unwrap().iter().map(|m| m.payload.data));
+        }
+        panic!("replay did not finish, read {:?}", data);
+    }
+
+    fn replay(broker: &InMemoryBroker, session: NodeStreamSessionId) -> TestConsumer {
+        NodeStreamConsumer::with_epoch_range(
+            broker.backend(),
+            Some(session),
+            0..=1,
+            topic(),
+            NodeStreamConsumerConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn restarted_replay_finishes_epochs_already_read() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..3);
+        publish(&broker, &topic(), 1, 3..5);
+        let session = NodeStreamSessionId::new();
+        assert_eq!(
+            drain_replay(&mut replay(&broker, session)),
+            vec![0, 1, 2, 3, 4]
+        );
+
+        let mut restarted = replay(&broker, session);
+        assert_eq!(drain_replay(&mut restarted), Vec::<u64>::new());
+        let progress = restarted.epoch_progress();
+        assert_eq!(progress.len(), 2);
+        assert!(progress.iter().all(|p| p.complete && p.messages == 0));
+        assert_eq!(progress[0].positions, BTreeMap::from([(0, 3)]));
+    }
+
+    fn rollover_config(epoch_rollover: EpochRollover) -> NodeStreamConsumerConfig {
+        NodeStreamConsumerConfig {
+            epoch_rollover,
+            rollover_check_interval: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    fn rolling_consumer(broker: &InMemoryBroker, epoch_rollover: EpochRollover) -> TestConsumer {
+        test_utils::consumer(broker, None, &topic(), 0, rollover_config(epoch_rollover))
+    }
+
+    /// Data of the messages returned by `polls` polls.
+    fn poll_data<B: NodeStreamBackend>(
+        consumer: &mut NodeStreamConsumer<TestTopic, u64, String, B>,
+        polls: usize,
+    ) -> Vec<u64> {
+        (0..polls)
+            .flat_map(|_| consumer.poll().unwrap())
+            .map(|m| m.payload.data)
+            .collect()
+    }
+
+    fn send_epoch_end(broker: &InMemoryBroker, epoch: u64) {
+        NodeStreamProducer::with_backend(broker.backend())
+            .send_epoch_end(epoch, topic())
+            .unwrap();
+    }
+
+    #[test]
+    fn rolls_over_after_the_epoch_end_marker() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..2);
+        send_epoch_end(&broker, 0);
+        publish(&broker, &topic(), 1, 2..4);
+
+        let mut consumer = rolling_consumer(&broker, EpochRollover::OnEpochEndMarker);
+        assert_eq!(poll_data(&mut consumer, 4), vec![0, 1, 2, 3]);
+        assert_eq!(consumer.epoch(), 1);
+    }
+
+    #[test]
+    fn rolls_over_once_every_partition_is_marked() {
+        let broker = InMemoryBroker::new();
+        let backend = PartitionedBackend::new(&broker, 2);
+        let mut producer = NodeStreamProducer::with_backend(backend.clone());
+        for data in 0..4 {
+            producer.send(0, topic(), &payload(data)).unwrap();
+        }
+        producer.send_epoch_end(0, topic()).unwrap();
+        producer.send(1, topic(), &payload(4)).unwrap();
+
+        backend.hold(1);
+        let config = rollover_config(EpochRollover::OnEpochEndMarker);
+        let mut consumer =
+            NodeStreamConsumer::with_config(backend.clone(), None, 0, topic(), config).unwrap();
+        // The marker on partition 0 says nothing about the records left on 1
+        assert_eq!(poll_data(&mut consumer, 4), vec![0, 2]);
+        assert_eq!(consumer.epoch(), 0);
+
+        backend.release(1);
+        assert_eq!(poll_data(&mut consumer, 6), vec![1, 3, 4]);
+        assert_eq!(consumer.epoch(), 1);
+    }
+
+    #[test]
+    fn marked_epochs_wait_for_the_next_epoch_topic() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..2);
+        send_epoch_end(&broker, 0);
+
+        let mut consumer = rolling_consumer(&broker, EpochRollover::OnEpochEndMarker);
+        assert_eq!(poll_data(&mut consumer, 3), vec![0, 1]);
+        assert_eq!(consumer.epoch(), 0);
+
+        publish(&broker, &topic(), 1, 2..3);
+        assert_eq!(poll_data(&mut consumer, 2), vec![2]);
+        assert_eq!(consumer.epoch(), 1);
+    }
+
+    #[test]
+    fn unmarked_epochs_only_roll_over_on_next_epoch_records() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..2);
+        NodeStreamProducer::with_backend(broker.backend())
+            .provision_epoch(1, topic(), &Default::default())
+            .unwrap();
+
+        // A provisioned topic without records is not enough
+        let mut on_next_topic = rolling_consumer(&broker, EpochRollover::OnNextEpochTopic);
+        assert_eq!(poll_data(&mut on_next_topic, 3), vec![0, 1]);
+        assert_eq!(on_next_topic.epoch(), 0);
+
+        publish(&broker, &topic(), 1, 2..3);
+        assert_eq!(poll_data(&mut on_next_topic, 2), vec![2]);
+        assert_eq!(on_next_topic.epoch(), 1);
+
+        for rollover in [EpochRollover::OnEpochEndMarker, EpochRollover::Disabled] {
+            let mut consumer = rolling_consumer(&broker, rollover);
+            assert_eq!(poll_data(&mut consumer, 3), vec![0, 1]);
+            assert_eq!(consumer.epoch(), 0, "{:?}", rollover);
+        }
+    }
+
+    /// Publishes 0..2, a record which does not decode, then 3..5.
+    fn publish_with_poison(broker: &InMemoryBroker) -> Vec<u8> {
+        let poison = vec![0xff; 3];
+        publish(broker, &topic(), 0, 0..2);
+        publish_raw(broker, &topic(), 0, poison.clone());
+        publish(broker, &topic(), 0, 3..5);
+        poison
+    }
+
+    fn poison_consumer(
+        broker: &InMemoryBroker,
+        poison_policy: NodeStreamPoisonPolicy,
+    ) -> TestConsumer {
+        let config = NodeStreamConsumerConfig {
+            poison_policy,
+            ..Default::default()
+        };
+        test_utils::consumer(broker, None, &topic(), 0, config)
+    }
+
+    fn dead_letters(broker: &InMemoryBroker) -> Vec<NodeStreamDeadLetter> {
+        let mut backend = broker.backend();
+        let dead_letter_topic = topic().topic_for_epoch(0).dead_letter();
+        backend
+            .subscribe(
+                &dead_letter_topic,
+                "reader",
+                &NodeStreamStartPosition::Earliest,
+            )
+            .unwrap();
+        backend
+            .poll()
+            .unwrap()
+            .iter()
+            .map(|r| NodeStreamDeadLetter::from_bytes(&r.value).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn skip_moves_past_undecodable_messages() {
+        let broker = InMemoryBroker::new();
+        publish_with_poison(&broker);
+        let mut consumer = poison_consumer(&broker, NodeStreamPoisonPolicy::Skip);
+        assert_eq!(poll_data(&mut consumer, 1), vec![0, 1, 3, 4]);
+        assert!(broker
+            .topic_len(&topic().topic_for_epoch(0).dead_letter())
+            .is_none());
+    }
+
+    #[test]
+    fn dead_letters_forward_undecodable_messages() {
+        let broker = InMemoryBroker::new();
+        let poison = publish_with_poison(&broker);
+        let mut consumer = poison_consumer(&broker, NodeStreamPoisonPolicy::DeadLetter);
+        assert_eq!(poll_data(&mut consumer, 1), vec![0, 1, 3, 4]);
+
+        let dead_letters = dead_letters(&broker);
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter = &dead_letters[0];
+        assert_eq!(
+            (&dead_letter.topic, dead_letter.epoch, dead_letter.offset),
+            (&topic().topic_for_epoch(0), 0, 2)
+        );
+        assert_eq!(dead_letter.payload, poison);
+        assert!(dead_letter.error.starts_with("PayloadDeserializeError"));
+    }
 }
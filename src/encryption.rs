@@ -0,0 +1,281 @@
+use crate::envelope::NodeStreamEnvelopeHeader;
+use crate::types::NodeStreamTopic;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use std::collections::BTreeMap;
+
+/// Envelope header holding the key id and nonce of an encrypted payload.
+pub const ENCRYPTION_HEADER: &str = "encryption.aes-256-gcm";
+
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM keys by id, with the epochs each one encrypts.
+///
+/// Producers encrypt an epoch with the key rotated in last at or before it.
+/// Consumers pick keys by the id recorded in each record, so retired keys
+/// must stay in the ring for as long as their epochs are read. Nonces are
+/// random, so a key should not encrypt more than a few billion records.
+#[derive(Clone, Default)]
+pub struct NodeStreamKeyRing {
+    keys: BTreeMap<String, Aes256Gcm>,
+    rotations: BTreeMap<u64, String>,
+}
+
+/// Whether the payload of the envelope with `header` is encrypted.
+pub(crate) fn is_encrypted(header: &NodeStreamEnvelopeHeader) -> bool {
+    header.headers.contains_key(ENCRYPTION_HEADER)
+}
+
+impl NodeStreamKeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key consumers can decrypt with.
+    pub fn with_key(mut self, id: impl Into<String>, key: [u8; 32]) -> Self {
+        self.keys.insert(id.into(), Aes256Gcm::new(&key.into()));
+        self
+    }
+
+    /// Makes producers encrypt with key `id` from `epoch` on.
+    pub fn rotate_at(mut self, epoch: u64, id: impl Into<String>) -> Self {
+        self.rotations.insert(epoch, id.into());
+        self
+    }
+
+    /// Id of the key producers encrypt `epoch` with.
+    pub fn key_id_for_epoch(&self, epoch: u64) -> Option<&str> {
+        self.rotations
+            .range(..=epoch)
+            .next_back()
+            .map(|(_, id)| id.as_str())
+    }
+
+    /// Encrypts `payload` for `topic`, recording the key id and nonce in `header`.
+    pub(crate) fn encrypt(
+        &self,
+        header: &mut NodeStreamEnvelopeHeader,
+        epoch: u64,
+        topic: &NodeStreamTopic,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let id = self
+            .key_id_for_epoch(epoch)
+            .ok_or_else(|| format!("no key rotated in at or before epoch {}", epoch))?;
+        let cipher = self
+            .keys
+            .get(id)
+            .ok_or_else(|| format!("unknown key id {}", id))?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: payload,
+                    aad: topic.topic.as_bytes(),
+                },
+            )
+            .map_err(|_| "encryption failed".to_string())?;
+        header.headers.insert(
+            ENCRYPTION_HEADER.to_string(),
+            bcs::to_bytes(&(id, nonce)).expect("encryption headers are always serializable"),
+        );
+        Ok(ciphertext)
+    }
+
+    /// Decrypts `payload`, read from `topic`. Payloads which were not
+    /// encrypted are returned as is, see `require_encryption` to reject them.
+    pub(crate) fn decrypt(
+        keys: Option<&Self>,
+        header: &NodeStreamEnvelopeHeader,
+        topic: &NodeStreamTopic,
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let value = match header.headers.get(ENCRYPTION_HEADER) {
+            Some(value) => value,
+            None => return Ok(payload),
+        };
+        let (id, nonce) = bcs::from_bytes::<(String, [u8; NONCE_LEN])>(value)
+            .map_err(|err| format!("malformed encryption header: {}", err))?;
+        let cipher = keys
+            .and_then(|keys| keys.keys.get(&id))
+            .ok_or_else(|| format!("unknown key id {}", id))?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &payload,
+                    aad: topic.topic.as_bytes(),
+                },
+            )
+            .map_err(|_| format!("unable to decrypt with key id {}", id))
+    }
+}
+
+impl std::fmt::Debug for NodeStreamKeyRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeStreamKeyRing")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("rotations", &self.rotations)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::NodeStreamConsumerConfig;
+    use crate::memory::InMemoryBroker;
+    use crate::producer::{NodeStreamProducer, NodeStreamProducerConfig};
+    use crate::test_utils::{self, header, payload, poll_err, TestConsumer, TestTopic};
+    use crate::types::{NodeStreamConsumerError, NodeStreamEnvelopeError};
+
+    fn ring() -> NodeStreamKeyRing {
+        NodeStreamKeyRing::new()
+            .with_key("first", [1; 32])
+            .with_key("second", [2; 32])
+            .rotate_at(0, "first")
+            .rotate_at(10, "second")
+    }
+
+    fn topic() -> TestTopic {
+        TestTopic::new("encryption-test-")
+    }
+
+    fn consumer(
+        broker: &InMemoryBroker,
+        epoch: u64,
+        config: NodeStreamConsumerConfig,
+    ) -> TestConsumer {
+        test_utils::consumer(broker, None, &topic(), epoch, config)
+    }
+
+    #[test]
+    fn keys_rotate_at_their_epoch() {
+        let ring = ring().with_key("third", [3; 32]).rotate_at(25, "third");
+        for (epoch, id) in [
+            (0, "first"),
+            (9, "first"),
+            (10, "second"),
+            (24, "second"),
+            (25, "third"),
+            (u64::MAX, "third"),
+        ] {
+            assert_eq!(ring.key_id_for_epoch(epoch), Some(id), "{}", epoch);
+        }
+        let late = NodeStreamKeyRing::new()
+            .with_key("late", [1; 32])
+            .rotate_at(5, "late");
+        assert_eq!(late.key_id_for_epoch(4), None);
+        assert!(late
+            .encrypt(
+                &mut header(),
+                4,
+                &NodeStreamTopic::new("t".to_string()),
+                b"x"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn ciphertext_only_decrypts_on_its_topic() {
+        let topic = NodeStreamTopic::new("encrypted".to_string());
+        let other = NodeStreamTopic::new("replayed".to_string());
+        let mut header = header();
+        let ciphertext = ring().encrypt(&mut header, 0, &topic, b"secret").unwrap();
+        assert_ne!(ciphertext, b"secret");
+        assert_eq!(
+            NodeStreamKeyRing::decrypt(Some(&ring()), &header, &topic, ciphertext.clone()),
+            Ok(b"secret".to_vec())
+        );
+        assert!(NodeStreamKeyRing::decrypt(Some(&ring()), &header, &other, ciphertext).is_err());
+    }
+
+    #[test]
+    fn consumers_decrypt_epochs_on_both_sides_of_a_rotation() {
+        let broker = InMemoryBroker::new();
+        let config = NodeStreamProducerConfig {
+            encryption: Some(ring()),
+            ..Default::default()
+        };
+        let mut producer = NodeStreamProducer::with_config(broker.backend(), config);
+        producer.send(9, topic(), &payload(9)).unwrap();
+        producer.send(10, topic(), &payload(10)).unwrap();
+
+        for epoch in [9, 10] {
+            let config = NodeStreamConsumerConfig {
+                decryption_keys: Some(ring()),
+                require_encryption: true,
+                ..Default::default()
+            };
+            let messages = consumer(&broker, epoch, config).poll().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].payload.data, epoch);
+            let (id, _) = bcs::from_bytes::<(String, [u8; NONCE_LEN])>(
+                &messages[0].envelope.as_ref().unwrap().headers[ENCRYPTION_HEADER],
+            )
+            .unwrap();
+            assert_eq!(id, if epoch < 10 { "first" } else { "second" });
+        }
+
+        // A consumer which was only given the new key cannot read the old epoch
+        let config = NodeStreamConsumerConfig {
+            decryption_keys: Some(NodeStreamKeyRing::new().with_key("second", [2; 32])),
+            ..Default::default()
+        };
+        assert!(matches!(
+            consumer(&broker, 9, config).poll(),
+            Err(NodeStreamConsumerError::UnableToDecrypt { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn required_encryption_rejects_plaintext_and_legacy_records() {
+        let broker = InMemoryBroker::new();
+        for config in [
+            NodeStreamProducerConfig::default(),
+            NodeStreamProducerConfig {
+                legacy_records: true,
+                ..Default::default()
+            },
+            NodeStreamProducerConfig {
+                encryption: Some(ring()),
+                ..Default::default()
+            },
+        ] {
+            let mut producer = NodeStreamProducer::with_config(broker.backend(), config);
+            producer.send(0, topic(), &payload(0)).unwrap();
+        }
+
+        let config = NodeStreamConsumerConfig {
+            decryption_keys: Some(ring()),
+            ..Default::default()
+        };
+        assert_eq!(
+            consumer(&broker, 0, config.clone()).poll().unwrap().len(),
+            3
+        );
+
+        let mut consumer = consumer(
+            &broker,
+            0,
+            NodeStreamConsumerConfig {
+                require_encryption: true,
+                ..config
+            },
+        );
+        for offset in [0, 1] {
+            match poll_err(&mut consumer, offset) {
+                NodeStreamConsumerError::InvalidEnvelope {
+                    offset: failed,
+                    err: NodeStreamEnvelopeError::NotEncrypted,
+                    ..
+                } => assert_eq!(failed, offset),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(consumer.poll().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::codec::NodeStreamCodecId;
+use crate::types::NodeStreamEnvelopeError;
+use blake2::{digest::consts::U32, Blake2b};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+// Wire format of an enveloped record:
+//
+//   ENVELOPE_MAGIC | envelope version (u8) | bcs((NodeStreamEnvelopeHeader, payload bytes))
+//
+// Records which do not start with ENVELOPE_MAGIC are legacy records, holding
+// the serialized payload only. A legacy payload may start with ENVELOPE_MAGIC
+// too, so consumers accepting legacy records read a record starting with it
+// whose envelope has an unknown version or does not decode to its last byte
+// as a legacy record, if its payload decodes. A legacy payload which also
+// decodes as a whole envelope is misread; that collision is accepted.
+
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"\0NSE";
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Metadata written by producers in front of every payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStreamEnvelopeHeader {
+    /// Version of the payload's schema, see `NodeStreamPerEpochTopic::schema_version`.
+    pub schema_version: u32,
+    pub codec: NodeStreamCodecId,
+    pub producer_id: String,
+    /// Milliseconds since the unix epoch at which the producer sent the record.
+    pub produced_at_ms: u64,
+    /// Extra headers, keyed by name.
+    pub headers: BTreeMap<String, Vec<u8>>,
+}
+
+/// Digest producers can add to envelopes, computed over the serialized payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStreamChecksumAlgorithm {
+    Crc32c,
+    Sha256,
+    Blake2b256,
+}
+
+impl NodeStreamChecksumAlgorithm {
+    const ALL: [NodeStreamChecksumAlgorithm; 3] = [Self::Crc32c, Self::Sha256, Self::Blake2b256];
+
+    /// Envelope header holding the digest.
+    pub fn header(&self) -> &'static str {
+        match self {
+            Self::Crc32c => "checksum.crc32c",
+            Self::Sha256 => "checksum.sha256",
+            Self::Blake2b256 => "checksum.blake2b-256",
+        }
+    }
+
+    pub fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Crc32c => crc32c::crc32c(bytes).to_le_bytes().to_vec(),
+            Self::Sha256 => Sha256::digest(bytes).to_vec(),
+            Self::Blake2b256 => Blake2b::<U32>::digest(bytes).to_vec(),
+        }
+    }
+}
+
+impl NodeStreamEnvelopeHeader {
+    pub(crate) fn add_checksum(&mut self, algorithm: NodeStreamChecksumAlgorithm, payload: &[u8]) {
+        self.headers
+            .insert(algorithm.header().to_string(), algorithm.digest(payload));
+    }
+
+    /// Verifies every checksum of the envelope against `payload`. Returns
+    /// whether there was any, or the algorithm whose digest did not match.
+    pub(crate) fn verify_checksums(
+        &self,
+        payload: &[u8],
+    ) -> Result<bool, NodeStreamChecksumAlgorithm> {
+        let mut found = false;
+        for algorithm in NodeStreamChecksumAlgorithm::ALL {
+            if let Some(digest) = self.headers.get(algorithm.header()) {
+                if *digest != algorithm.digest(payload) {
+                    return Err(algorithm);
+                }
+                found = true;
+            }
+        }
+        Ok(found)
+    }
+}
+
+pub(crate) fn seal(header: &NodeStreamEnvelopeHeader, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.push(ENVELOPE_VERSION);
+    bytes.extend(bcs::to_bytes(&(header, payload)).expect("envelopes are always serializable"));
+    bytes
+}
+
+/// Splits an enveloped record into its header and payload. Returns `None`
+/// for legacy records.
+pub(crate) fn open(
+    bytes: &[u8],
+) -> Result<Option<(NodeStreamEnvelopeHeader, Vec<u8>)>, NodeStreamEnvelopeError> {
+    let rest = match bytes.strip_prefix(ENVELOPE_MAGIC.as_slice()) {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    match rest.split_first() {
+        Some((&ENVELOPE_VERSION, body)) => {
+            bcs::from_bytes(body)
+                .map(Some)
+                .map_err(|err| NodeStreamEnvelopeError::Malformed {
+                    err: err.to_string(),
+                })
+        }
+        Some((version, _)) => {
+            Err(NodeStreamEnvelopeError::UnsupportedVersion { version: *version })
+        }
+        None => Err(NodeStreamEnvelopeError::Malformed {
+            err: "missing envelope version".to_string(),
+        }),
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::NodeStreamConsumerConfig;
+    use crate::memory::InMemoryBroker;
+    use crate::producer::{NodeStreamProducer, NodeStreamProducerConfig};
+    use crate::test_utils::{consumer, header, payload, poll_err, publish_raw, TestTopic};
+    use crate::types::{NodeStreamConsumerError, NodeStreamPerEpochTopic};
+
+    fn topic() -> TestTopic {
+        TestTopic::new("envelope-test-")
+    }
+
+    fn encoded(data: u64) -> Vec<u8> {
+        topic().payload_to_bytes(&payload(data)).unwrap()
+    }
+
+    #[test]
+    fn checksums_detect_changed_payloads() {
+        for algorithm in NodeStreamChecksumAlgorithm::ALL {
+            let mut header = header();
+            assert_eq!(header.verify_checksums(b"payload"), Ok(false));
+            header.add_checksum(algorithm, b"payload");
+            assert_eq!(header.verify_checksums(b"payload"), Ok(true));
+            assert_eq!(header.verify_checksums(b"paylobd"), Err(algorithm));
+            assert_eq!(header.verify_checksums(b""), Err(algorithm));
+        }
+
+        // Every checksum present has to match
+        let mut header = header();
+        header.add_checksum(NodeStreamChecksumAlgorithm::Crc32c, b"payload");
+        header.add_checksum(NodeStreamChecksumAlgorithm::Sha256, b"other");
+        assert_eq!(
+            header.verify_checksums(b"payload"),
+            Err(NodeStreamChecksumAlgorithm::Sha256)
+        );
+    }
+
+    #[test]
+    fn consumers_reject_changed_payloads() {
+        let broker = InMemoryBroker::new();
+        for algorithm in NodeStreamChecksumAlgorithm::ALL {
+            let mut header = header();
+            header.add_checksum(algorithm, &encoded(1));
+            publish_raw(&broker, &topic(), 0, seal(&header, &encoded(2)));
+        }
+
+        let mut consumer = consumer(&broker, None, &topic(), 0, Default::default());
+        for (offset, algorithm) in NodeStreamChecksumAlgorithm::ALL.into_iter().enumerate() {
+            match poll_err(&mut consumer, offset as i64) {
+                NodeStreamConsumerError::ChecksumMismatch {
+                    topic: failed_topic,
+                    offset: failed_offset,
+                    algorithm: failed_algorithm,
+                } => assert_eq!(
+                    (failed_topic, failed_offset, failed_algorithm),
+                    (topic().topic_for_epoch(0), offset as i64, algorithm)
+                ),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn required_checksums_reject_records_without_one() {
+        let broker = InMemoryBroker::new();
+        for config in [
+            NodeStreamProducerConfig::default(),
+            NodeStreamProducerConfig {
+                legacy_records: true,
+                ..Default::default()
+            },
+            NodeStreamProducerConfig {
+                checksum: Some(NodeStreamChecksumAlgorithm::Blake2b256),
+                ..Default::default()
+            },
+        ] {
+            NodeStreamProducer::with_config(broker.backend(), config)
+                .send(0, topic(), &payload(0))
+                .unwrap();
+        }
+
+        let config = NodeStreamConsumerConfig {
+            require_checksum: true,
+            ..Default::default()
+        };
+        let mut consumer = consumer(&broker, None, &topic(), 0, config);
+        for offset in [0, 1] {
+            assert!(matches!(
+                poll_err(&mut consumer, offset),
+                NodeStreamConsumerError::InvalidEnvelope {
+                    err: NodeStreamEnvelopeError::MissingChecksum,
+                    ..
+                }
+            ));
+        }
+        assert_eq!(consumer.poll().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn legacy_payloads_starting_with_the_magic_are_read_as_legacy() {
+        let broker = InMemoryBroker::new();
+        // An empty metdata serializes to 0, then data starts with "NSE" and a version
+        for version in [ENVELOPE_VERSION, ENVELOPE_VERSION + 1] {
+            let data = u64::from_le_bytes([b'N', b'S', b'E', version, 0, 0, 0, 0]);
+            let mut legacy = payload(data);
+            legacy.metdata = String::new();
+            let bytes = topic().payload_to_bytes(&legacy).unwrap();
+            assert!(bytes.starts_with(ENVELOPE_MAGIC));
+            assert!(open(&bytes).is_err());
+            publish_raw(&broker, &topic(), 0, bytes);
+        }
+        // Records which decode as neither still fail with the envelope error
+        let mut garbled = seal(&header(), &encoded(0));
+        garbled.push(0);
+        publish_raw(&broker, &topic(), 0, garbled);
+
+        let mut legacy = consumer(&broker, None, &topic(), 0, Default::default());
+        let messages = legacy.poll().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.envelope.is_none()));
+        assert!(matches!(
+            poll_err(&mut legacy, 2),
+            NodeStreamConsumerError::InvalidEnvelope {
+                err: NodeStreamEnvelopeError::Malformed { .. },
+                ..
+            }
+        ));
+
+        // Without legacy records the envelope errors stand
+        let config = NodeStreamConsumerConfig {
+            accept_legacy_records: false,
+            ..Default::default()
+        };
+        let mut consumer = consumer(&broker, None, &topic(), 0, config);
+        assert!(matches!(
+            poll_err(&mut consumer, 0),
+            NodeStreamConsumerError::InvalidEnvelope {
+                err: NodeStreamEnvelopeError::Malformed { .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            poll_err(&mut consumer, 1),
+            NodeStreamConsumerError::InvalidEnvelope {
+                err: NodeStreamEnvelopeError::UnsupportedVersion { .. },
+                ..
+            }
+        ));
+    }
+}
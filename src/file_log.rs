@@ -0,0 +1,873 @@
+use crate::backend::{
+    NodeStreamBackend, NodeStreamDeliveryReceipt, NodeStreamFetchedRecord,
+    NodeStreamPartitionMetadata, NodeStreamPartitionOffset, NodeStreamRecord,
+    NodeStreamStartPosition, NodeStreamTopicConfig, NodeStreamTopicMetadata,
+};
+use crate::types::{NodeStreamBackendError, NodeStreamTopic};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Layout of a topic directory:
+//
+//   <root>/<topic>/<base offset>.log     length-prefixed records (u32 LE length, bytes)
+//   <root>/<topic>/<base offset>.index   u64 LE position in the .log of each record
+//   <root>/<topic>/groups/<group id>     next offset to read for that group (u64 LE)
+//   <root>/<topic>/writer.lock           held by the only backend appending to the topic
+//
+// A record is only visible once its index entry is written. Unless every record
+// is synced, a crash can leave either file ahead of the other on disk, so both
+// are truncated after the last whole record when the topic is reopened.
+
+/// File-log topics have a single partition.
+const FILE_LOG_PARTITION: i32 = 0;
+const LOG_EXTENSION: &str = "log";
+const INDEX_EXTENSION: &str = "index";
+const GROUPS_DIR: &str = "groups";
+const WRITER_LOCK: &str = "writer.lock";
+const INDEX_ENTRY_SIZE: u64 = 8;
+const RECORD_HEADER_SIZE: u64 = 4;
+const POLL_IDLE_SLEEP: Duration = Duration::from_millis(100);
+const POLL_MAX_RECORDS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave flushing to the OS.
+    Never,
+    /// Sync the log and index after every record.
+    Always,
+    /// Sync the log and index after this many records.
+    EveryRecords(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct FileLogConfig {
+    /// A new segment is started once the current one reaches this size.
+    pub segment_max_bytes: u64,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for FileLogConfig {
+    fn default() -> Self {
+        Self {
+            segment_max_bytes: 64 * 1024 * 1024,
+            fsync: FsyncPolicy::EveryRecords(1000),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SegmentWriter {
+    log: File,
+    index: File,
+    log_size: u64,
+    next_offset: u64,
+    unsynced: u64,
+}
+
+impl SegmentWriter {
+    fn create(dir: &Path, base_offset: u64) -> std::io::Result<Self> {
+        let open = |ext| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(dir, base_offset, ext))
+        };
+        Ok(Self {
+            log: open(LOG_EXTENSION)?,
+            index: open(INDEX_EXTENSION)?,
+            log_size: 0,
+            next_offset: base_offset,
+            unsynced: 0,
+        })
+    }
+
+    /// Opens the last segment of `dir`, dropping the records at its end which
+    /// are missing from the index or whose bytes did not fully reach the log.
+    fn recover(dir: &Path, base_offset: u64) -> std::io::Result<Self> {
+        let log_path = segment_path(dir, base_offset, LOG_EXTENSION);
+        let index_path = segment_path(dir, base_offset, INDEX_EXTENSION);
+        let index_len = fs::metadata(&index_path).map(|m| m.len()).unwrap_or(0);
+        let log_len = fs::metadata(&log_path)?.len();
+        let mut log = File::open(&log_path)?;
+
+        let mut entries = index_len / INDEX_ENTRY_SIZE;
+        let mut log_size = 0;
+        while entries > 0 {
+            let position = read_index_entry(&index_path, entries - 1)?;
+            if position + RECORD_HEADER_SIZE <= log_len {
+                let end =
+                    position + RECORD_HEADER_SIZE + read_record_len(&mut log, position)? as u64;
+                if end <= log_len {
+                    log_size = end;
+                    break;
+                }
+            }
+            entries -= 1;
+        }
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&index_path)?
+            .set_len(entries * INDEX_ENTRY_SIZE)?;
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(log_size)?;
+
+        let mut writer = Self::create(dir, base_offset)?;
+        writer.log_size = log_size;
+        writer.next_offset = base_offset + entries;
+        Ok(writer)
+    }
+
+    fn append(&mut self, value: &[u8], fsync: FsyncPolicy) -> std::io::Result<u64> {
+        let len = u32::try_from(value.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("record of {} bytes is too large for the log", value.len()),
+            )
+        })?;
+        let position = self.log_size;
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE as usize + value.len());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(value);
+        self.log.write_all(&buf)?;
+        self.index.write_all(&position.to_le_bytes())?;
+        self.log_size += buf.len() as u64;
+        self.unsynced += 1;
+
+        let sync = match fsync {
+            FsyncPolicy::Never => false,
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryRecords(n) => self.unsynced >= n,
+        };
+        if sync {
+            self.sync()?;
+        }
+
+        let offset = self.next_offset;
+        self.next_offset += 1;
+        Ok(offset)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.log.sync_data()?;
+        self.index.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Segment a subscription is reading, kept open between polls.
+#[derive(Debug)]
+struct SegmentReader {
+    base_offset: u64,
+    log: File,
+    index: File,
+}
+
+impl SegmentReader {
+    fn open(dir: &Path, base_offset: u64) -> std::io::Result<Self> {
+        Ok(Self {
+            base_offset,
+            log: File::open(segment_path(dir, base_offset, LOG_EXTENSION))?,
+            index: File::open(segment_path(dir, base_offset, INDEX_EXTENSION))?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    topic: NodeStreamTopic,
+    group_id: String,
+    position: u64,
+    reader: Option<SegmentReader>,
+}
+
+/// Append-only log on the local filesystem, one directory per topic under `root`.
+///
+/// A topic is appended to by one backend at a time: the first backend to
+/// write to it keeps it locked until dropped, and other backends writing to
+/// it, in this process or another, fail with `TopicLocked`.
+#[derive(Debug)]
+pub struct FileLogBackend {
+    root: PathBuf,
+    config: FileLogConfig,
+    writers: HashMap<String, SegmentWriter>,
+    /// Writer locks held, by topic.
+    locks: HashMap<String, File>,
+    subscription: Option<Subscription>,
+}
+
+impl FileLogBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_config(root, FileLogConfig::default())
+    }
+
+    pub fn with_config(root: impl Into<PathBuf>, config: FileLogConfig) -> Self {
+        Self {
+            root: root.into(),
+            config,
+            writers: HashMap::new(),
+            locks: HashMap::new(),
+            subscription: None,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn topic_dir(&self, topic: &NodeStreamTopic) -> Result<PathBuf, NodeStreamBackendError> {
+        // Kafka's naming rules also keep names safe to use as a directory name
+        topic
+            .validate()
+            .map_err(|_| NodeStreamBackendError::InvalidTopicName {
+                topic: topic.clone(),
+            })?;
+        Ok(self.root.join(&topic.topic))
+    }
+
+    fn group_offset_path(
+        &self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+    ) -> Result<PathBuf, NodeStreamBackendError> {
+        // Held to the same rules as topic names, so it stays a plain file name
+        NodeStreamTopic::new(group_id.to_string())
+            .validate()
+            .map_err(|_| NodeStreamBackendError::InvalidGroupId {
+                group_id: group_id.to_string(),
+            })?;
+        Ok(self.topic_dir(topic)?.join(GROUPS_DIR).join(group_id))
+    }
+
+    fn writer(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<&mut SegmentWriter, NodeStreamBackendError> {
+        let dir = self.topic_dir(topic)?;
+        let name = topic.to_raw();
+        if !self.writers.contains_key(&name) {
+            fs::create_dir_all(dir.join(GROUPS_DIR)).map_err(io_err)?;
+            self.lock(topic, &dir)?;
+            let writer = match list_segments(&dir).map_err(io_err)?.last() {
+                Some(base) => SegmentWriter::recover(&dir, *base),
+                None => SegmentWriter::create(&dir, 0),
+            }
+            .map_err(io_err)?;
+            self.writers.insert(name.clone(), writer);
+        }
+
+        let writer = self.writers.get_mut(&name).unwrap();
+        if writer.log_size >= self.config.segment_max_bytes {
+            // Only the last segment is recovered after a crash, so the
+            // segment left behind must be whole on disk before a new one starts
+            if self.config.fsync != FsyncPolicy::Never {
+                writer.sync().map_err(io_err)?;
+            }
+            *writer = SegmentWriter::create(&dir, writer.next_offset).map_err(io_err)?;
+        }
+        Ok(writer)
+    }
+
+    /// Takes the writer lock of `topic`, kept until the backend is dropped.
+    fn lock(&mut self, topic: &NodeStreamTopic, dir: &Path) -> Result<(), NodeStreamBackendError> {
+        if self.locks.contains_key(&topic.topic) {
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(WRITER_LOCK))
+            .map_err(io_err)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(NodeStreamBackendError::TopicLocked {
+                    topic: topic.clone(),
+                })
+            }
+            Err(TryLockError::Error(err)) => return Err(io_err(err)),
+        }
+        self.locks.insert(topic.to_raw(), file);
+        Ok(())
+    }
+
+    /// Offset the next record appended to `topic` will get, or `None` if the
+    /// topic does not exist.
+    fn high_watermark(
+        &self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<u64>, NodeStreamBackendError> {
+        let dir = self.topic_dir(topic)?;
+        let segments = match list_segments(&dir) {
+            Ok(segments) => segments,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(io_err(err)),
+        };
+        Ok(Some(match segments.last() {
+            Some(base) => {
+                let index_len = fs::metadata(segment_path(&dir, *base, INDEX_EXTENSION))
+                    .map_err(io_err)?
+                    .len();
+                base + index_len / INDEX_ENTRY_SIZE
+            }
+            None => 0,
+        }))
+    }
+
+    fn resolve_position(
+        &self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<u64, NodeStreamBackendError> {
+        let end = self.high_watermark(topic)?.unwrap_or_default();
+        Ok(match position {
+            NodeStreamStartPosition::Earliest => 0,
+            NodeStreamStartPosition::Latest => end,
+            NodeStreamStartPosition::Offsets(offsets) => offsets
+                .get(&FILE_LOG_PARTITION)
+                .map_or(0, |o| (*o).clamp(0, end as i64) as u64),
+            NodeStreamStartPosition::FromEnd(n) => end.saturating_sub(*n),
+            NodeStreamStartPosition::Timestamp(_) => {
+                return Err(NodeStreamBackendError::Unsupported {
+                    operation: "timestamp start positions".to_string(),
+                })
+            }
+        })
+    }
+
+    fn write_group_offset(
+        &self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        next: u64,
+    ) -> Result<(), NodeStreamBackendError> {
+        let path = self.group_offset_path(topic, group_id)?;
+        fs::create_dir_all(path.parent().unwrap()).map_err(io_err)?;
+
+        // Write then rename so a crash never leaves a half-written offset behind
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp).map_err(io_err)?;
+        file.write_all(&next.to_le_bytes()).map_err(io_err)?;
+        if self.config.fsync != FsyncPolicy::Never {
+            file.sync_data().map_err(io_err)?;
+        }
+        fs::rename(&tmp, &path).map_err(io_err)
+    }
+}
+
+impl NodeStreamBackend for FileLogBackend {
+    fn publish(
+        &mut self,
+        topic: &NodeStreamTopic,
+        record: NodeStreamRecord,
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamBackendError> {
+        record.ensure_single_partition()?;
+        let fsync = self.config.fsync;
+        let offset = self
+            .writer(topic)?
+            .append(&record.value, fsync)
+            .map_err(io_err)?;
+        Ok(NodeStreamDeliveryReceipt {
+            topic: topic.clone(),
+            partition: 0,
+            offset: offset as i64,
+        })
+    }
+
+    fn subscribe(
+        &mut self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        start: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let position = match fs::read(self.group_offset_path(topic, group_id)?) {
+            Ok(bytes) => u64::from_le_bytes(bytes.as_slice().try_into().map_err(|_| {
+                NodeStreamBackendError::CorruptLog {
+                    topic: topic.clone(),
+                    reason: format!("bad committed offset for group {}", group_id),
+                }
+            })?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                self.resolve_position(topic, start)?
+            }
+            Err(err) => return Err(io_err(err)),
+        };
+        self.subscription = Some(Subscription {
+            topic: topic.clone(),
+            group_id: group_id.to_string(),
+            position,
+            reader: None,
+        });
+        Ok(())
+    }
+
+    fn positions(&mut self) -> Result<Vec<NodeStreamPartitionOffset>, NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .as_ref()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        Ok(vec![NodeStreamPartitionOffset {
+            partition: FILE_LOG_PARTITION,
+            offset: sub.position as i64,
+        }])
+    }
+
+    fn poll(&mut self) -> Result<Vec<NodeStreamFetchedRecord>, NodeStreamBackendError> {
+        let topic = self
+            .subscription
+            .as_ref()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?
+            .topic
+            .clone();
+        let dir = self.topic_dir(&topic)?;
+        let segments = match list_segments(&dir) {
+            Ok(segments) => segments,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(io_err(err)),
+        };
+
+        let sub = self.subscription.as_mut().unwrap();
+        let mut records = vec![];
+        let mut position = sub.position;
+        for (i, base) in segments.iter().enumerate() {
+            let next_base = segments.get(i + 1).copied().unwrap_or(u64::MAX);
+            if position >= next_base || records.len() >= POLL_MAX_RECORDS {
+                continue;
+            }
+            if position < *base {
+                return Err(NodeStreamBackendError::CorruptLog {
+                    topic: topic.clone(),
+                    reason: format!("offsets {} to {} are missing", position, base - 1),
+                });
+            }
+            if sub.reader.as_ref().is_none_or(|r| r.base_offset != *base) {
+                sub.reader = Some(SegmentReader::open(&dir, *base).map_err(io_err)?);
+            }
+            let reader = sub.reader.as_mut().unwrap();
+            let entries = reader.index.metadata().map_err(io_err)?.len() / INDEX_ENTRY_SIZE;
+            if position >= base + entries {
+                continue;
+            }
+
+            // Only read the index entries of the records polled
+            let count = (base + entries - position).min((POLL_MAX_RECORDS - records.len()) as u64);
+            let mut index = vec![0u8; (count * INDEX_ENTRY_SIZE) as usize];
+            reader
+                .index
+                .seek(SeekFrom::Start((position - base) * INDEX_ENTRY_SIZE))
+                .map_err(io_err)?;
+            reader.index.read_exact(&mut index).map_err(io_err)?;
+            for entry in index.chunks_exact(INDEX_ENTRY_SIZE as usize) {
+                let file_pos = u64::from_le_bytes(entry.try_into().unwrap());
+                let len = read_record_len(&mut reader.log, file_pos).map_err(io_err)?;
+                let mut value = vec![0u8; len as usize];
+                reader.log.read_exact(&mut value).map_err(|_| {
+                    NodeStreamBackendError::CorruptLog {
+                        topic: topic.clone(),
+                        reason: format!("truncated record at offset {}", position),
+                    }
+                })?;
+                records.push(NodeStreamFetchedRecord {
+                    topic: topic.clone(),
+                    partition: FILE_LOG_PARTITION,
+                    offset: position as i64,
+                    value,
+                });
+                position += 1;
+            }
+        }
+
+        if records.is_empty() {
+            std::thread::sleep(POLL_IDLE_SLEEP);
+        }
+        sub.position = position;
+        Ok(records)
+    }
+
+    fn commit(
+        &mut self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) -> Result<(), NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .as_ref()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        match offsets.iter().map(|o| o.offset).max() {
+            Some(offset) => self.write_group_offset(topic, &sub.group_id, (offset + 1) as u64),
+            None => Ok(()),
+        }
+    }
+
+    fn seek(
+        &mut self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let position = self.resolve_position(topic, position)?;
+        let sub = self
+            .subscription
+            .as_mut()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        sub.position = position;
+        let group_id = sub.group_id.clone();
+        self.write_group_offset(topic, &group_id, position)
+    }
+
+    fn topic_metadata(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamBackendError> {
+        Ok(self
+            .high_watermark(topic)?
+            .map(|high_watermark| NodeStreamTopicMetadata {
+                topic: topic.clone(),
+                partitions: vec![NodeStreamPartitionMetadata {
+                    partition: FILE_LOG_PARTITION,
+                    leader: None,
+                    high_watermark: high_watermark as i64,
+                }],
+            }))
+    }
+
+    fn create_topic(
+        &mut self,
+        topic: &NodeStreamTopic,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamBackendError> {
+        config.ensure_single_partition()?;
+        if self.high_watermark(topic)?.is_some() {
+            return Ok(());
+        }
+        match self.writer(topic) {
+            Ok(_) => {
+                // Leave the topic to whichever backend appends to it
+                self.writers.remove(&topic.topic);
+                self.locks.remove(&topic.topic);
+                Ok(())
+            }
+            // Created by another backend in the meantime
+            Err(NodeStreamBackendError::TopicLocked { .. }) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn delete_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        let dir = self.topic_dir(topic)?;
+        if dir.exists() {
+            // Not from under another backend still writing to it
+            self.lock(topic, &dir)?;
+        }
+        self.writers.remove(&topic.topic);
+        self.locks.remove(&topic.topic);
+        match fs::remove_dir_all(dir) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(io_err(err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn list_topics(&mut self) -> Result<Vec<NodeStreamTopic>, NodeStreamBackendError> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(io_err(err)),
+        };
+        let mut topics = vec![];
+        for entry in entries {
+            let entry = entry.map_err(io_err)?;
+            if !entry.file_type().map_err(io_err)?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                let topic = NodeStreamTopic::new(name.to_string());
+                if self.topic_dir(&topic).is_ok() {
+                    topics.push(topic);
+                }
+            }
+        }
+        topics.sort_unstable_by(|a, b| a.topic.cmp(&b.topic));
+        Ok(topics)
+    }
+}
+
+fn io_err(err: std::io::Error) -> NodeStreamBackendError {
+    NodeStreamBackendError::Io { err }
+}
+
+fn segment_path(dir: &Path, base_offset: u64, extension: &str) -> PathBuf {
+    dir.join(format!("{:020}.{}", base_offset, extension))
+}
+
+/// Base offsets of the segments in `dir`, in ascending order.
+fn list_segments(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut segments = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != LOG_EXTENSION {
+                return None;
+            }
+            path.file_stem()?.to_str()?.parse::<u64>().ok()
+        })
+        .collect::<Vec<_>>();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+fn read_index_entry(index_path: &Path, entry: u64) -> std::io::Result<u64> {
+    let mut index = File::open(index_path)?;
+    index.seek(SeekFrom::Start(entry * INDEX_ENTRY_SIZE))?;
+    let mut buf = [0u8; INDEX_ENTRY_SIZE as usize];
+    index.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Seeks `log` to the record at `position` and reads its length, leaving the
+/// cursor at the start of the record's bytes.
+fn read_record_len(log: &mut File, position: u64) -> std::io::Result<u32> {
+    log.seek(SeekFrom::Start(position))?;
+    let mut buf = [0u8; RECORD_HEADER_SIZE as usize];
+    log.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "node-stream-file-log-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ))
+    }
+
+    fn record(value: &[u8]) -> NodeStreamRecord {
+        NodeStreamRecord {
+            key: None,
+            value: value.to_vec(),
+            partition: None,
+        }
+    }
+
+    fn read_all(root: &Path, topic: &NodeStreamTopic) -> Vec<Vec<u8>> {
+        let mut backend = FileLogBackend::new(root);
+        backend
+            .subscribe(topic, "test", &NodeStreamStartPosition::Earliest)
+            .unwrap();
+        backend
+            .poll()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.value)
+            .collect()
+    }
+
+    #[test]
+    fn recover_drops_records_not_fully_in_the_log() {
+        let root = temp_root();
+        let topic = NodeStreamTopic::new("recover".to_string());
+        let log_path = segment_path(&root.join("recover"), 0, LOG_EXTENSION);
+        let config = FileLogConfig {
+            fsync: FsyncPolicy::Never,
+            ..Default::default()
+        };
+        let mut backend = FileLogBackend::with_config(&root, config.clone());
+        for value in [b"aaaaaaaaaa", b"bbbbbbbbbb", b"cccccccccc"] {
+            backend.publish(&topic, record(value)).unwrap();
+        }
+        drop(backend);
+
+        // The last record's length reached the log but not all of its bytes,
+        // while its index entry did
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(2 * 14 + 6)
+            .unwrap();
+        let mut backend = FileLogBackend::with_config(&root, config.clone());
+        let receipt = backend.publish(&topic, record(b"dddddddddd")).unwrap();
+        assert_eq!(receipt.offset, 2);
+        drop(backend);
+        assert_eq!(
+            read_all(&root, &topic),
+            vec![
+                b"aaaaaaaaaa".to_vec(),
+                b"bbbbbbbbbb".to_vec(),
+                b"dddddddddd".to_vec()
+            ]
+        );
+
+        // Not even the last record's length reached the log
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(2 * 14 + 2)
+            .unwrap();
+        let mut backend = FileLogBackend::with_config(&root, config);
+        let receipt = backend.publish(&topic, record(b"eeeeeeeeee")).unwrap();
+        assert_eq!(receipt.offset, 2);
+        drop(backend);
+        assert_eq!(
+            read_all(&root, &topic),
+            vec![
+                b"aaaaaaaaaa".to_vec(),
+                b"bbbbbbbbbb".to_vec(),
+                b"eeeeeeeeee".to_vec()
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn recover_drops_records_missing_from_the_index() {
+        let root = temp_root();
+        let topic = NodeStreamTopic::new("unindexed".to_string());
+        let mut backend = FileLogBackend::new(&root);
+        for value in [b"aaaaaaaaaa", b"bbbbbbbbbb"] {
+            backend.publish(&topic, record(value)).unwrap();
+        }
+        drop(backend);
+
+        let index_path = segment_path(&root.join("unindexed"), 0, INDEX_EXTENSION);
+        OpenOptions::new()
+            .write(true)
+            .open(index_path)
+            .unwrap()
+            .set_len(INDEX_ENTRY_SIZE + 3)
+            .unwrap();
+        let mut backend = FileLogBackend::new(&root);
+        assert_eq!(backend.publish(&topic, record(b"c")).unwrap().offset, 1);
+        drop(backend);
+        assert_eq!(
+            read_all(&root, &topic),
+            vec![b"aaaaaaaaaa".to_vec(), b"c".to_vec()]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn missing_offsets_are_reported_as_corrupt() {
+        let root = temp_root();
+        let topic = NodeStreamTopic::new("gap".to_string());
+        let config = FileLogConfig {
+            segment_max_bytes: 1,
+            fsync: FsyncPolicy::Never,
+        };
+        let mut backend = FileLogBackend::with_config(&root, config);
+        for value in [b"a", b"b", b"c"] {
+            backend.publish(&topic, record(value)).unwrap();
+        }
+        drop(backend);
+
+        // The tail of a segment other than the last one was lost
+        let index_path = segment_path(&root.join("gap"), 1, INDEX_EXTENSION);
+        OpenOptions::new()
+            .write(true)
+            .open(index_path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        let mut backend = FileLogBackend::new(&root);
+        backend
+            .subscribe(&topic, "test", &NodeStreamStartPosition::Earliest)
+            .unwrap();
+        assert!(matches!(
+            backend.poll(),
+            Err(NodeStreamBackendError::CorruptLog { .. })
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn group_ids_stay_within_the_topic() {
+        let root = temp_root();
+        let topic = NodeStreamTopic::new("groups".to_string());
+        let mut backend = FileLogBackend::new(&root);
+        for group_id in ["../escape", "a/b", "..", ""] {
+            assert!(
+                matches!(
+                    backend.subscribe(&topic, group_id, &NodeStreamStartPosition::Earliest),
+                    Err(NodeStreamBackendError::InvalidGroupId { .. })
+                ),
+                "{}",
+                group_id
+            );
+        }
+        backend
+            .subscribe(
+                &topic,
+                "0A1B-group_id.x",
+                &NodeStreamStartPosition::Earliest,
+            )
+            .unwrap();
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn polls_read_across_segments() {
+        let root = temp_root();
+        let topic = NodeStreamTopic::new("segments".to_string());
+        let config = FileLogConfig {
+            segment_max_bytes: 20,
+            fsync: FsyncPolicy::Always,
+        };
+        let mut writer = FileLogBackend::with_config(&root, config);
+        let mut reader = FileLogBackend::new(&root);
+        reader
+            .subscribe(&topic, "test", &NodeStreamStartPosition::Earliest)
+            .unwrap();
+        let mut read = vec![];
+        for i in 0..10u8 {
+            writer.publish(&topic, record(&[i; 7])).unwrap();
+            if i % 3 == 0 {
+                read.extend(reader.poll().unwrap());
+            }
+        }
+        read.extend(reader.poll().unwrap());
+        assert_eq!(
+            read.iter().map(|r| r.offset).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert!(read.iter().all(|r| r.value == [r.offset as u8; 7]));
+        assert_eq!(list_segments(&root.join("segments")).unwrap().len(), 5);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn one_writer_per_topic() {
+        let root = temp_root();
+        let topic = NodeStreamTopic::new("locked".to_string());
+        let mut writer = FileLogBackend::new(&root);
+        writer.publish(&topic, record(b"a")).unwrap();
+
+        let mut other = FileLogBackend::new(&root);
+        assert!(matches!(
+            other.publish(&topic, record(b"b")),
+            Err(NodeStreamBackendError::TopicLocked { .. })
+        ));
+        assert!(matches!(
+            other.delete_topic(&topic),
+            Err(NodeStreamBackendError::TopicLocked { .. })
+        ));
+        other
+            .create_topic(&topic, &NodeStreamTopicConfig::default())
+            .unwrap();
+        writer.publish(&topic, record(b"c")).unwrap();
+
+        drop(writer);
+        assert_eq!(other.publish(&topic, record(b"d")).unwrap().offset, 2);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
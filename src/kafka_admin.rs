@@ -0,0 +1,429 @@
+//! The CreateTopics and DeleteTopics requests of the Kafka protocol, which
+//! kafka-rust does not implement. Requests are sent to the cluster's
+//! controller, found through a metadata request to the bootstrap broker.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+const METADATA: i16 = 3;
+const CREATE_TOPICS: i16 = 19;
+const DELETE_TOPICS: i16 = 20;
+
+// Oldest versions letting the broker pick the partition count and
+// replication factor, and still supported by Kafka 4
+const METADATA_VERSION: i16 = 4;
+const CREATE_TOPICS_VERSION: i16 = 4;
+const DELETE_TOPICS_VERSION: i16 = 1;
+
+const CLIENT_ID: &str = "node-stream-admin";
+
+pub(crate) const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+pub(crate) const TOPIC_ALREADY_EXISTS: i16 = 36;
+const REQUEST_TIMED_OUT: i16 = 7;
+const NOT_CONTROLLER: i16 = 41;
+
+/// Whether a request failing with `code` may succeed when sent again.
+pub(crate) fn is_retryable_code(code: i16) -> bool {
+    matches!(code, REQUEST_TIMED_OUT | NOT_CONTROLLER)
+}
+
+/// Outcome of a request for a single topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KafkaTopicResult {
+    pub error_code: i16,
+    pub message: Option<String>,
+}
+
+pub(crate) struct KafkaAdminClient {
+    bootstrap: SocketAddr,
+    timeout: Duration,
+    correlation_id: i32,
+}
+
+impl KafkaAdminClient {
+    /// `timeout` bounds each network operation, and how long the controller
+    /// waits for a topic change to complete.
+    pub fn new(bootstrap: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            bootstrap,
+            timeout,
+            correlation_id: 0,
+        }
+    }
+
+    /// Creates `topic`. A partition count or replication factor of -1 leaves
+    /// it to the broker's `num.partitions` and `default.replication.factor`.
+    pub fn create_topic(
+        &mut self,
+        topic: &str,
+        partitions: i32,
+        replication_factor: i16,
+    ) -> io::Result<KafkaTopicResult> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_str(&mut body, topic);
+        put_i32(&mut body, partitions);
+        put_i16(&mut body, replication_factor);
+        // No manual assignments, no topic configs
+        put_i32(&mut body, 0);
+        put_i32(&mut body, 0);
+        put_i32(&mut body, self.timeout_ms());
+        // validate_only
+        body.push(0);
+
+        let response = self.send_to_controller(CREATE_TOPICS, CREATE_TOPICS_VERSION, &body)?;
+        let mut reader = Reader::new(&response);
+        let _throttle_time_ms = reader.i32()?;
+        let results = reader.i32()?;
+        for _ in 0..results {
+            let name = reader.string()?;
+            let error_code = reader.i16()?;
+            let message = reader.nullable_string()?;
+            if name == topic {
+                return Ok(KafkaTopicResult {
+                    error_code,
+                    message,
+                });
+            }
+        }
+        Err(missing_topic(topic))
+    }
+
+    pub fn delete_topic(&mut self, topic: &str) -> io::Result<KafkaTopicResult> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_str(&mut body, topic);
+        put_i32(&mut body, self.timeout_ms());
+
+        let response = self.send_to_controller(DELETE_TOPICS, DELETE_TOPICS_VERSION, &body)?;
+        let mut reader = Reader::new(&response);
+        let _throttle_time_ms = reader.i32()?;
+        let results = reader.i32()?;
+        for _ in 0..results {
+            let name = reader.string()?;
+            let error_code = reader.i16()?;
+            if name == topic {
+                return Ok(KafkaTopicResult {
+                    error_code,
+                    message: None,
+                });
+            }
+        }
+        Err(missing_topic(topic))
+    }
+
+    fn timeout_ms(&self) -> i32 {
+        self.timeout.as_millis().min(i32::MAX as u128) as i32
+    }
+
+    fn send_to_controller(
+        &mut self,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let mut bootstrap = self.connect(self.bootstrap)?;
+        let controller = self.controller(&mut bootstrap)?;
+        let mut stream = match controller {
+            Some(addr) => self.connect(addr)?,
+            None => bootstrap,
+        };
+        self.request(&mut stream, api_key, api_version, body)
+    }
+
+    /// Address of the controller, `None` if the cluster does not know it yet.
+    fn controller(&mut self, stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+        let mut body = Vec::new();
+        // No topics, only the brokers are needed
+        put_i32(&mut body, 0);
+        // allow_auto_topic_creation
+        body.push(0);
+
+        let response = self.request(stream, METADATA, METADATA_VERSION, &body)?;
+        let mut reader = Reader::new(&response);
+        let _throttle_time_ms = reader.i32()?;
+        let mut brokers = Vec::new();
+        for _ in 0..reader.i32()? {
+            let node_id = reader.i32()?;
+            let host = reader.string()?;
+            let port = reader.i32()?;
+            let _rack = reader.nullable_string()?;
+            brokers.push((node_id, host, port));
+        }
+        let _cluster_id = reader.nullable_string()?;
+        let controller_id = reader.i32()?;
+        let Some((_, host, port)) = brokers.into_iter().find(|(id, _, _)| *id == controller_id)
+        else {
+            return Ok(None);
+        };
+        let port = u16::try_from(port).map_err(|_| invalid_data("invalid broker port"))?;
+        (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .map(Some)
+            .ok_or_else(|| invalid_data("controller address did not resolve"))
+    }
+
+    fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    /// Sends a request and returns the response body, after its header.
+    fn request(
+        &mut self,
+        stream: &mut TcpStream,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = Vec::new();
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, api_version);
+        put_i32(&mut request, self.correlation_id);
+        put_str(&mut request, CLIENT_ID);
+        request.extend_from_slice(body);
+        write_frame(stream, &request)?;
+
+        let response = read_frame(stream)?;
+        let mut reader = Reader::new(&response);
+        if reader.i32()? != self.correlation_id {
+            return Err(invalid_data("response to another request"));
+        }
+        Ok(reader.rest().to_vec())
+    }
+}
+
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len = i32::try_from(payload.len()).map_err(|_| invalid_data("request too large"))?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = usize::try_from(i32::from_be_bytes(len)).map_err(|_| invalid_data("bad frame"))?;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn put_i16(buf: &mut Vec<u8>, v: i16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, v: i32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_i16(buf, s.len() as i16);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn missing_topic(topic: &str) -> io::Error {
+    invalid_data(&format!("response has no result for topic {}", topic))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid_data("truncated response"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn nullable_string(&mut self) -> io::Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|_| invalid_data("string is not utf-8"))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.nullable_string()?
+            .ok_or_else(|| invalid_data("unexpected null string"))
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    struct Request {
+        api_key: i16,
+        api_version: i16,
+        body: Vec<u8>,
+    }
+
+    /// A broker answering each request it gets with the next of `responses`,
+    /// and returning the requests once they are all answered.
+    fn fake_broker(listener: TcpListener, responses: Vec<Vec<u8>>) -> JoinHandle<Vec<Request>> {
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            let mut responses = responses.into_iter();
+            'connections: for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                while let Ok(frame) = read_frame(&mut stream) {
+                    let mut reader = Reader::new(&frame);
+                    let api_key = reader.i16().unwrap();
+                    let api_version = reader.i16().unwrap();
+                    let correlation_id = reader.i32().unwrap();
+                    assert_eq!(reader.string().unwrap(), CLIENT_ID);
+                    requests.push(Request {
+                        api_key,
+                        api_version,
+                        body: reader.rest().to_vec(),
+                    });
+
+                    let mut response = Vec::new();
+                    put_i32(&mut response, correlation_id);
+                    response.extend(responses.next().unwrap());
+                    write_frame(&mut stream, &response).unwrap();
+                    if responses.len() == 0 {
+                        break 'connections;
+                    }
+                }
+            }
+            requests
+        })
+    }
+
+    fn metadata_response(controller: SocketAddr) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 0);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, 7);
+        put_str(&mut body, &controller.ip().to_string());
+        put_i32(&mut body, controller.port() as i32);
+        put_i16(&mut body, -1);
+        put_i16(&mut body, -1);
+        put_i32(&mut body, 7);
+        put_i32(&mut body, 0);
+        body
+    }
+
+    fn listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[test]
+    fn topics_are_created_on_the_controller() {
+        let (bootstrap, bootstrap_addr) = listener();
+        let (controller, controller_addr) = listener();
+        let bootstrap = fake_broker(bootstrap, vec![metadata_response(controller_addr)]);
+        let mut created = Vec::new();
+        put_i32(&mut created, 0);
+        put_i32(&mut created, 1);
+        put_str(&mut created, "topic");
+        put_i16(&mut created, TOPIC_ALREADY_EXISTS);
+        put_str(&mut created, "exists");
+        let controller = fake_broker(controller, vec![created]);
+
+        let mut client = KafkaAdminClient::new(bootstrap_addr, Duration::from_secs(5));
+        assert_eq!(
+            client.create_topic("topic", 3, -1).unwrap(),
+            KafkaTopicResult {
+                error_code: TOPIC_ALREADY_EXISTS,
+                message: Some("exists".to_string()),
+            }
+        );
+
+        let metadata = &bootstrap.join().unwrap()[0];
+        assert_eq!(
+            (metadata.api_key, metadata.api_version),
+            (METADATA, METADATA_VERSION)
+        );
+        let create = &controller.join().unwrap()[0];
+        assert_eq!(
+            (create.api_key, create.api_version),
+            (CREATE_TOPICS, CREATE_TOPICS_VERSION)
+        );
+        let mut reader = Reader::new(&create.body);
+        assert_eq!(reader.i32().unwrap(), 1);
+        assert_eq!(reader.string().unwrap(), "topic");
+        assert_eq!(reader.i32().unwrap(), 3);
+        assert_eq!(reader.i16().unwrap(), -1);
+        assert_eq!(reader.i32().unwrap(), 0);
+        assert_eq!(reader.i32().unwrap(), 0);
+        assert_eq!(reader.i32().unwrap(), 5000);
+        assert_eq!(reader.rest(), [0]);
+    }
+
+    #[test]
+    fn topics_are_deleted_through_the_bootstrap_broker_without_a_controller() {
+        let (bootstrap, bootstrap_addr) = listener();
+        let mut unknown_controller = metadata_response(bootstrap_addr);
+        let len = unknown_controller.len();
+        unknown_controller[len - 8..len - 4].copy_from_slice(&(-1i32).to_be_bytes());
+        let mut deleted = Vec::new();
+        put_i32(&mut deleted, 0);
+        put_i32(&mut deleted, 1);
+        put_str(&mut deleted, "topic");
+        put_i16(&mut deleted, 0);
+        let bootstrap = fake_broker(bootstrap, vec![unknown_controller, deleted]);
+
+        let mut client = KafkaAdminClient::new(bootstrap_addr, Duration::from_secs(5));
+        assert_eq!(
+            client.delete_topic("topic").unwrap(),
+            KafkaTopicResult {
+                error_code: 0,
+                message: None,
+            }
+        );
+
+        let requests = bootstrap.join().unwrap();
+        let delete = &requests[1];
+        assert_eq!(
+            (delete.api_key, delete.api_version),
+            (DELETE_TOPICS, DELETE_TOPICS_VERSION)
+        );
+        let mut reader = Reader::new(&delete.body);
+        assert_eq!(reader.i32().unwrap(), 1);
+        assert_eq!(reader.string().unwrap(), "topic");
+        assert_eq!(reader.i32().unwrap(), 5000);
+        assert!(reader.rest().is_empty());
+    }
+}
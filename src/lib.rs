@@ -1,3 +1,21 @@
+pub mod ack;
+pub mod admin;
+pub mod async_producer;
+pub mod backend;
+pub mod chunking;
+pub mod codec;
+pub mod compression;
 pub mod consumer;
-pub mod types;
+pub mod encryption;
+pub mod envelope;
+pub mod file_log;
+mod kafka_admin;
+pub mod memory;
 pub mod producer;
+pub mod retry;
+pub mod signature;
+pub mod stream;
+#[cfg(test)]
+pub(crate) mod test_utils;
+pub mod topics;
+pub mod types;
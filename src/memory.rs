@@ -0,0 +1,441 @@
+use crate::backend::{
+    NodeStreamBackend, NodeStreamDeliveryReceipt, NodeStreamFetchedRecord,
+    NodeStreamPartitionMetadata, NodeStreamPartitionOffset, NodeStreamRecord,
+    NodeStreamStartPosition, NodeStreamTopicConfig, NodeStreamTopicMetadata,
+};
+use crate::types::{NodeStreamBackendError, NodeStreamTopic};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// In-memory topics have a single partition.
+const IN_MEMORY_PARTITION: i32 = 0;
+const POLL_MAX_WAIT: Duration = Duration::from_millis(100);
+const POLL_MAX_RECORDS: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct InMemoryBrokerConfig {
+    /// Most records kept per topic. Publishing more drops the oldest ones,
+    /// and consumers behind them carry on from the oldest record left.
+    pub max_records_per_topic: usize,
+}
+
+impl Default for InMemoryBrokerConfig {
+    fn default() -> Self {
+        Self {
+            max_records_per_topic: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TopicLog {
+    /// Offset of the oldest record kept.
+    start: i64,
+    records: VecDeque<Vec<u8>>,
+}
+
+impl TopicLog {
+    /// Offset the next record is written at.
+    fn end(&self) -> i64 {
+        self.start + self.records.len() as i64
+    }
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    /// Append-only log per topic, trimmed to the configured retention.
+    logs: HashMap<String, TopicLog>,
+    /// Next offset to read, keyed by (group id, topic).
+    committed: HashMap<(String, String), i64>,
+}
+
+#[derive(Debug, Default)]
+struct BrokerShared {
+    config: InMemoryBrokerConfig,
+    state: Mutex<BrokerState>,
+    appended: Condvar,
+}
+
+/// An in-process broker. Clones share the same topics and committed offsets,
+/// so a producer and any number of consumers can each hold a backend created
+/// from the same broker.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryBroker {
+    shared: Arc<BrokerShared>,
+}
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: InMemoryBrokerConfig) -> Self {
+        Self {
+            shared: Arc::new(BrokerShared {
+                config,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn backend(&self) -> InMemoryBackend {
+        InMemoryBackend {
+            broker: self.clone(),
+            subscription: None,
+        }
+    }
+
+    /// Number of records kept in `topic`, or `None` if nothing was ever
+    /// published to it.
+    pub fn topic_len(&self, topic: &NodeStreamTopic) -> Option<usize> {
+        self.state().logs.get(&topic.topic).map(|l| l.records.len())
+    }
+
+    fn state(&self) -> MutexGuard<'_, BrokerState> {
+        self.shared
+            .state
+            .lock()
+            .expect("in-memory broker lock poisoned")
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    topic: NodeStreamTopic,
+    group_id: String,
+    position: i64,
+}
+
+/// Backend handle onto an `InMemoryBroker`.
+#[derive(Debug)]
+pub struct InMemoryBackend {
+    broker: InMemoryBroker,
+    subscription: Option<Subscription>,
+}
+
+impl InMemoryBackend {
+    pub fn broker(&self) -> &InMemoryBroker {
+        &self.broker
+    }
+}
+
+impl Clone for InMemoryBackend {
+    fn clone(&self) -> Self {
+        self.broker.backend()
+    }
+}
+
+impl NodeStreamBackend for InMemoryBackend {
+    fn publish(
+        &mut self,
+        topic: &NodeStreamTopic,
+        record: NodeStreamRecord,
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamBackendError> {
+        record.ensure_single_partition()?;
+        let max_records = self.broker.shared.config.max_records_per_topic;
+        let mut state = self.broker.state();
+        let log = state.logs.entry(topic.to_raw()).or_default();
+        let offset = log.end();
+        log.records.push_back(record.value);
+        while log.records.len() > max_records {
+            log.records.pop_front();
+            log.start += 1;
+        }
+        drop(state);
+        self.broker.shared.appended.notify_all();
+        Ok(NodeStreamDeliveryReceipt {
+            topic: topic.clone(),
+            partition: 0,
+            offset,
+        })
+    }
+
+    fn subscribe(
+        &mut self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        start: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let state = self.broker.state();
+        let position = match state.committed.get(&(group_id.to_string(), topic.to_raw())) {
+            Some(position) => *position,
+            None => resolve_position(&state, topic, start)?,
+        };
+        drop(state);
+        self.subscription = Some(Subscription {
+            topic: topic.clone(),
+            group_id: group_id.to_string(),
+            position,
+        });
+        Ok(())
+    }
+
+    fn seek(
+        &mut self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .as_mut()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        let mut state = self.broker.state();
+        sub.position = resolve_position(&state, topic, position)?;
+        state
+            .committed
+            .insert((sub.group_id.clone(), topic.to_raw()), sub.position);
+        Ok(())
+    }
+
+    fn positions(&mut self) -> Result<Vec<NodeStreamPartitionOffset>, NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .as_ref()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        // Records before the start were dropped by retention
+        let start = self
+            .broker
+            .state()
+            .logs
+            .get(&sub.topic.topic)
+            .map_or(0, |l| l.start);
+        Ok(vec![NodeStreamPartitionOffset {
+            partition: IN_MEMORY_PARTITION,
+            offset: sub.position.max(start),
+        }])
+    }
+
+    fn poll(&mut self) -> Result<Vec<NodeStreamFetchedRecord>, NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .as_mut()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        let topic_str = sub.topic.to_raw();
+
+        let available = |state: &BrokerState| {
+            state
+                .logs
+                .get(&topic_str)
+                .is_some_and(|l| l.end() > sub.position)
+        };
+        let mut state = self.broker.state();
+        if !available(&state) {
+            state = self
+                .broker
+                .shared
+                .appended
+                .wait_timeout_while(state, POLL_MAX_WAIT, |s| !available(s))
+                .expect("in-memory broker lock poisoned")
+                .0;
+        }
+
+        let log = match state.logs.get(&topic_str) {
+            Some(log) => log,
+            None => return Ok(vec![]),
+        };
+        // Records before the start were dropped by retention
+        sub.position = sub.position.max(log.start);
+        let records = log
+            .records
+            .iter()
+            .zip(log.start..)
+            .skip((sub.position - log.start) as usize)
+            .take(POLL_MAX_RECORDS)
+            .map(|(value, offset)| NodeStreamFetchedRecord {
+                topic: sub.topic.clone(),
+                partition: IN_MEMORY_PARTITION,
+                offset,
+                value: value.clone(),
+            })
+            .collect::<Vec<_>>();
+        sub.position += records.len() as i64;
+        Ok(records)
+    }
+
+    fn commit(
+        &mut self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) -> Result<(), NodeStreamBackendError> {
+        let sub = self
+            .subscription
+            .as_ref()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        let mut state = self.broker.state();
+        for o in offsets {
+            let committed = state
+                .committed
+                .entry((sub.group_id.clone(), topic.to_raw()))
+                .or_default();
+            *committed = (*committed).max(o.offset + 1);
+        }
+        Ok(())
+    }
+
+    fn topic_metadata(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamBackendError> {
+        Ok(self
+            .broker
+            .state()
+            .logs
+            .get(&topic.topic)
+            .map(|log| NodeStreamTopicMetadata {
+                topic: topic.clone(),
+                partitions: vec![NodeStreamPartitionMetadata {
+                    partition: IN_MEMORY_PARTITION,
+                    leader: None,
+                    high_watermark: log.end(),
+                }],
+            }))
+    }
+
+    fn create_topic(
+        &mut self,
+        topic: &NodeStreamTopic,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamBackendError> {
+        config.ensure_single_partition()?;
+        self.broker.state().logs.entry(topic.to_raw()).or_default();
+        Ok(())
+    }
+
+    fn delete_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        let mut state = self.broker.state();
+        state.logs.remove(&topic.topic);
+        state.committed.retain(|(_, t), _| *t != topic.topic);
+        Ok(())
+    }
+
+    fn list_topics(&mut self) -> Result<Vec<NodeStreamTopic>, NodeStreamBackendError> {
+        let mut topics = self
+            .broker
+            .state()
+            .logs
+            .keys()
+            .map(|name| NodeStreamTopic::new(name.clone()))
+            .collect::<Vec<_>>();
+        topics.sort_unstable_by(|a, b| a.topic.cmp(&b.topic));
+        Ok(topics)
+    }
+}
+
+fn resolve_position(
+    state: &BrokerState,
+    topic: &NodeStreamTopic,
+    position: &NodeStreamStartPosition,
+) -> Result<i64, NodeStreamBackendError> {
+    let (start, end) = state
+        .logs
+        .get(&topic.topic)
+        .map_or((0, 0), |l| (l.start, l.end()));
+    Ok(match position {
+        NodeStreamStartPosition::Earliest => start,
+        NodeStreamStartPosition::Latest => end,
+        NodeStreamStartPosition::Offsets(offsets) => offsets
+            .get(&IN_MEMORY_PARTITION)
+            .copied()
+            .unwrap_or_default()
+            .clamp(start, end),
+        NodeStreamStartPosition::FromEnd(n) => (end - *n as i64).max(start),
+        NodeStreamStartPosition::Timestamp(_) => {
+            return Err(NodeStreamBackendError::Unsupported {
+                operation: "timestamp start positions".to_string(),
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::{NodeStreamCommitMode, NodeStreamConsumerConfig};
+    use crate::test_utils::{self, publish, TestConsumer, TestTopic};
+    use crate::types::{NodeStreamPerEpochTopic, NodeStreamSessionId};
+
+    fn topic() -> TestTopic {
+        TestTopic::new("memory-test-")
+    }
+
+    fn consumer(
+        broker: &InMemoryBroker,
+        session: NodeStreamSessionId,
+        commit_mode: NodeStreamCommitMode,
+    ) -> TestConsumer {
+        let config = NodeStreamConsumerConfig {
+            commit_mode,
+            ..Default::default()
+        };
+        test_utils::consumer(broker, Some(session), &topic(), 0, config)
+    }
+
+    /// (offset, data) of every message returned by the next poll.
+    fn poll(consumer: &mut TestConsumer) -> Vec<(i64, u64)> {
+        consumer
+            .poll()
+            .unwrap()
+            .iter()
+            .map(|m| (m.message_offset, m.payload.data))
+            .collect()
+    }
+
+    #[test]
+    fn groups_resume_from_their_commits() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..3);
+        let session = NodeStreamSessionId::new();
+        let mut first = consumer(&broker, session, NodeStreamCommitMode::Auto);
+        assert_eq!(poll(&mut first), vec![(0, 0), (1, 1), (2, 2)]);
+
+        publish(&broker, &topic(), 0, 3..5);
+        let mut resumed = consumer(&broker, session, NodeStreamCommitMode::Auto);
+        assert_eq!(poll(&mut resumed), vec![(3, 3), (4, 4)]);
+
+        // Another group reads the topic from the start
+        let mut other = consumer(
+            &broker,
+            NodeStreamSessionId::new(),
+            NodeStreamCommitMode::Auto,
+        );
+        assert_eq!(poll(&mut other).len(), 5);
+    }
+
+    #[test]
+    fn explicit_acks_resume_after_the_last_acked_message() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..4);
+        let session = NodeStreamSessionId::new();
+        let mut first = consumer(&broker, session, NodeStreamCommitMode::ExplicitAck);
+        let messages = first.poll().unwrap();
+        assert_eq!(messages.len(), 4);
+        messages[0].ack();
+        messages[2].ack();
+        first.commit_acked().unwrap();
+
+        // Message 1 was not acked, so it and everything after is read again
+        let mut resumed = consumer(&broker, session, NodeStreamCommitMode::ExplicitAck);
+        assert_eq!(poll(&mut resumed), vec![(1, 1), (2, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn retention_drops_the_oldest_records() {
+        let broker = InMemoryBroker::with_config(InMemoryBrokerConfig {
+            max_records_per_topic: 2,
+        });
+        let session = NodeStreamSessionId::new();
+        publish(&broker, &topic(), 0, 0..1);
+        let mut consumer = consumer(&broker, session, NodeStreamCommitMode::Auto);
+        publish(&broker, &topic(), 0, 1..5);
+
+        let topic = topic().topic_for_epoch(0);
+        assert_eq!(broker.topic_len(&topic), Some(2));
+        let metadata = broker.backend().topic_metadata(&topic).unwrap().unwrap();
+        assert_eq!(metadata.partitions[0].high_watermark, 5);
+        // The consumer was behind the records dropped and carries on from the oldest left
+        assert_eq!(poll(&mut consumer), vec![(3, 3), (4, 4)]);
+    }
+}
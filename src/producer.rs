@@ -1,28 +1,150 @@
+use crate::backend::{
+    KafkaBackend, NodeStreamBackend, NodeStreamDeliveryReceipt, NodeStreamPublishResult,
+    NodeStreamRecord, NodeStreamTopicConfig,
+};
+use crate::chunking;
+use crate::encryption::NodeStreamKeyRing;
+use crate::envelope::{self, NodeStreamChecksumAlgorithm, NodeStreamEnvelopeHeader};
+use crate::retry::RetryPolicy;
+use crate::signature::NodeStreamSigningKey;
 use crate::types::{
-    NodeStreamPerEpochTopic, NodeStreamProducerError, NodeStreamTopic, NodeStreamUserPayload,
+    NodeStreamBackendError, NodeStreamPerEpochTopic, NodeStreamProducerError, NodeStreamTopic,
+    NodeStreamUserPayload, EPOCH_END_MARKER,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
-use kafka::producer::{Producer, Record};
-use std::{net::SocketAddr, time::Duration};
 
-const TOPIC_REFRESH_TRIALS: u32 = 100;
-const TOPIC_REFRESH_SLEEP: Duration = Duration::from_millis(100);
-const PAYLOAD_SIZE_LIMIT: u64 = 1_000_000; // 1MB
+pub(crate) const PAYLOAD_SIZE_LIMIT: u64 = 1_000_000; // 1MB
+
+#[derive(Debug, Clone)]
+pub struct NodeStreamProducerConfig {
+    /// Identifies the producer in envelopes. Defaults to a random id.
+    pub producer_id: String,
+    /// Write bare payloads without an envelope, for consumers which predate
+    /// envelopes.
+    pub legacy_records: bool,
+    /// Digest added to envelopes so consumers can detect corrupted payloads.
+    pub checksum: Option<NodeStreamChecksumAlgorithm>,
+    /// Key envelopes are signed with, so consumers can tell who produced them.
+    pub signing_key: Option<NodeStreamSigningKey>,
+    /// Keys payloads are encrypted with. Checksums and signatures then cover
+    /// the encrypted payload.
+    pub encryption: Option<NodeStreamKeyRing>,
+    /// Split records over `PAYLOAD_SIZE_LIMIT` into chunks which consumers
+    /// reassemble, instead of refusing them. Not available for legacy records.
+    pub chunking: bool,
+    /// Largest record chunking applies to.
+    pub max_chunked_bytes: u64,
+    /// Applied to publishing. Records which failed with a retryable error, or
+    /// whose request failed as a whole, are sent again. So are the records
+    /// after them with the same key, even if they were published, so that the
+    /// last copy of each record keeps the order of its key. Delivery is
+    /// at-least-once: a request can also fail after the broker wrote its
+    /// records, e.g. on a timeout, which are then written again.
+    pub retry: RetryPolicy,
+}
+
+impl Default for NodeStreamProducerConfig {
+    fn default() -> Self {
+        Self {
+            producer_id: hex::encode(rand::random::<[u8; 8]>()),
+            legacy_records: false,
+            checksum: None,
+            signing_key: None,
+            encryption: None,
+            chunking: true,
+            max_chunked_bytes: 64 * 1024 * 1024,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Counters of a producer, shared by its clones.
+#[derive(Debug, Clone, Default)]
+pub struct NodeStreamProducerMetrics {
+    counters: Arc<ProducerCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ProducerCounters {
+    compressed_records: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+impl NodeStreamProducerMetrics {
+    pub fn compressed_records(&self) -> u64 {
+        self.counters.compressed_records.load(Ordering::Relaxed)
+    }
+
+    /// Size of compressed payloads before compression.
+    pub fn uncompressed_bytes(&self) -> u64 {
+        self.counters.uncompressed_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn compressed_bytes(&self) -> u64 {
+        self.counters.compressed_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Uncompressed over compressed size of the payloads compressed so far,
+    /// 1.0 if none was.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.compressed_bytes() {
+            0 => 1.0,
+            compressed => self.uncompressed_bytes() as f64 / compressed as f64,
+        }
+    }
+
+    fn record_compression(&self, uncompressed: usize, compressed: usize) {
+        let counters = &self.counters;
+        counters.compressed_records.fetch_add(1, Ordering::Relaxed);
+        counters
+            .uncompressed_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        counters
+            .compressed_bytes
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+}
 
-pub struct NodeStreamProducer {
-    host_addr: SocketAddr,
-    kafka_producer: Producer,
+pub struct NodeStreamProducer<B: NodeStreamBackend = KafkaBackend> {
+    backend: B,
+    config: NodeStreamProducerConfig,
+    metrics: NodeStreamProducerMetrics,
 }
-impl NodeStreamProducer {
+
+impl NodeStreamProducer<KafkaBackend> {
+    /// Connects to the cluster before returning, failing with
+    /// `UnableToCreateProducer` if it cannot be reached.
     pub fn new(host_addr: SocketAddr) -> Result<Self, NodeStreamProducerError> {
-        let kafka_producer = Producer::from_hosts(vec![host_addr.to_string()])
-            .create()
+        let backend = KafkaBackend::connect(host_addr)
             .map_err(|err| NodeStreamProducerError::UnableToCreateProducer { err })?;
-        Ok(Self {
-            host_addr,
-            kafka_producer,
-        })
+        Ok(Self::with_backend(backend))
+    }
+}
+
+impl<B: NodeStreamBackend> NodeStreamProducer<B> {
+    pub fn with_backend(backend: B) -> Self {
+        Self::with_config(backend, NodeStreamProducerConfig::default())
     }
 
+    pub fn with_config(backend: B, config: NodeStreamProducerConfig) -> Self {
+        Self {
+            backend,
+            config,
+            metrics: NodeStreamProducerMetrics::default(),
+        }
+    }
+
+    /// Publishes `payload` to `epoch`'s topic. The receipt tells where it was
+    /// written; for a chunked payload, where its last chunk was, which is the
+    /// offset consumers report for the reassembled message.
     pub fn send<
         T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
         D: std::fmt::Debug,
@@ -32,64 +154,479 @@ impl NodeStreamProducer {
         epoch: u64,
         topic: T,
         payload: &NodeStreamUserPayload<D, M>,
-    ) -> Result<(), NodeStreamProducerError> {
-        let topic_str = topic.topic_for_epoch(epoch).to_raw();
-        let bytes = topic.payload_to_bytes(payload).map_err(|err| {
-            NodeStreamProducerError::PayloadSerializeError {
-                topic: NodeStreamTopic {
-                    topic: topic_str.clone(),
-                },
-                err: format!("{:?}", err),
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamProducerError> {
+        let (topic, records) = encode_payload(&self.config, &self.metrics, epoch, &topic, payload)?;
+        publish_with_retry(&mut self.backend, &self.config.retry, &topic, records)
+            .and_then(payload_receipt)
+            .map_err(|err| NodeStreamProducerError::MessageSendFailed { err })
+    }
+
+    /// Publishes `payloads` to `epoch`'s topic in a single request where the
+    /// backend allows it, returning the outcome of each payload in order.
+    /// Payloads which cannot be serialized or are too large fail on their
+    /// own without holding back the others. Fails as a whole only if nothing
+    /// could be published.
+    pub fn send_batch<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        epoch: u64,
+        topic: T,
+        payloads: &[NodeStreamUserPayload<D, M>],
+    ) -> Result<
+        Vec<Result<NodeStreamDeliveryReceipt, NodeStreamProducerError>>,
+        NodeStreamProducerError,
+    > {
+        let topic_name = topic.topic_for_epoch(epoch);
+        let mut results = Vec::with_capacity(payloads.len());
+        // Records of the payloads which encoded, with the number of records of each
+        let mut records = vec![];
+        let mut record_counts = vec![];
+        for payload in payloads {
+            match encode_payload(&self.config, &self.metrics, epoch, &topic, payload) {
+                Ok((_, payload_records)) => {
+                    record_counts.push(Some(payload_records.len()));
+                    records.extend(payload_records);
+                }
+                Err(err) => {
+                    record_counts.push(None);
+                    results.push(Err(err));
+                }
             }
-        })?;
-        if bytes.len() as u64 > PAYLOAD_SIZE_LIMIT {
-            return Err(NodeStreamProducerError::PayloadTooLarge {
-                limit: PAYLOAD_SIZE_LIMIT,
-                size: bytes.len() as u64,
-            });
         }
-        let record = Record::from_value(&topic_str, bytes);
-
-        let mut num_trials = TOPIC_REFRESH_TRIALS;
-        // TODO:
-        // Check that topic exists before sending
-
-        // Make sure up to date
-        self.kafka_producer
-            .client_mut()
-            .load_metadata_all()
-            .map_err(|err| NodeStreamProducerError::UnableToLoadAllInternalMetatada { err })?;
-
-        // This will only happen first time this topis is hit
-        while !self.kafka_producer.client().topics().contains(&topic_str) && num_trials > 0 {
-            // Add the topic
-            self.kafka_producer
-                .client_mut()
-                .load_metadata(&[&topic_str])
-                .map_err(
-                    |err| NodeStreamProducerError::UnableToLoadTopicInternalMetatada {
-                        topic: topic.topic_for_epoch(epoch),
-                        err,
-                    },
-                )?;
-            num_trials -= 1;
-            std::thread::sleep(TOPIC_REFRESH_SLEEP);
-            // try to initialize again
-            self.kafka_producer = Producer::from_hosts(vec![self.host_addr.to_string()])
-                .create()
-                .map_err(|err| NodeStreamProducerError::UnableToCreateProducer { err })?;
+        if records.is_empty() {
+            return Ok(results);
         }
-        self.kafka_producer
-            .send(&record)
+
+        let mut published =
+            publish_with_retry(&mut self.backend, &self.config.retry, &topic_name, records)
+                .map_err(|err| NodeStreamProducerError::MessageSendFailed { err })?
+                .into_iter();
+        let mut failed = results.into_iter();
+        Ok(record_counts
+            .into_iter()
+            .map(|count| match count {
+                Some(count) => payload_receipt(published.by_ref().take(count).collect())
+                    .map_err(|err| NodeStreamProducerError::MessageSendFailed { err }),
+                None => failed
+                    .next()
+                    .expect("one error per payload which failed to encode"),
+            })
+            .collect())
+    }
+
+    /// Tells consumers rolling over epochs that nothing else will be sent to
+    /// `epoch`'s topic, with a marker on each of its partitions. The topic is
+    /// created first if nothing was sent to it.
+    pub fn send_epoch_end<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        epoch: u64,
+        topic: T,
+    ) -> Result<(), NodeStreamProducerError> {
+        let topic = topic.topic_for_epoch(epoch);
+        let markers = self
+            .partitions(&topic)
+            .map_err(|err| NodeStreamProducerError::MessageSendFailed { err })?
+            .into_iter()
+            .map(|partition| NodeStreamRecord {
+                key: None,
+                value: EPOCH_END_MARKER.to_vec(),
+                partition: Some(partition),
+            })
+            .collect();
+        publish_with_retry(&mut self.backend, &self.config.retry, &topic, markers)
+            .and_then(payload_receipt)
+            .map(|_| ())
             .map_err(|err| NodeStreamProducerError::MessageSendFailed { err })
     }
 
+    /// Partitions of `topic`, creating it with the backend's defaults if it
+    /// does not exist.
+    fn partitions(&mut self, topic: &NodeStreamTopic) -> Result<Vec<i32>, NodeStreamBackendError> {
+        let metadata = match self.backend.topic_metadata(topic)? {
+            Some(metadata) => metadata,
+            None => {
+                self.backend
+                    .create_topic(topic, &NodeStreamTopicConfig::default())?;
+                self.backend.topic_metadata(topic)?.ok_or_else(|| {
+                    NodeStreamBackendError::TopicNotCreated {
+                        topic: topic.clone(),
+                    }
+                })?
+            }
+        };
+        Ok(metadata.partitions.iter().map(|p| p.partition).collect())
+    }
+
+    /// Creates `epoch`'s topic ahead of the epoch change, so the first send
+    /// to it does not wait for the topic to be created. Consumers rolling
+    /// over with `EpochRollover::OnNextEpochTopic` wait for the topic to hold
+    /// records, so provisioning it does not move them on early.
+    pub fn provision_epoch<
+        T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug,
+        D: std::fmt::Debug,
+        M: std::fmt::Debug,
+    >(
+        &mut self,
+        epoch: u64,
+        topic: T,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamProducerError> {
+        let topic = topic.topic_for_epoch(epoch);
+        self.backend
+            .create_topic(&topic, config)
+            .map_err(|err| NodeStreamProducerError::UnableToProvisionTopic { topic, err })
+    }
+
+    pub fn metrics(&self) -> &NodeStreamProducerMetrics {
+        &self.metrics
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: NodeStreamBackend + Clone> NodeStreamProducer<B> {
+    /// Another producer with the same config on a copy of the backend,
+    /// sharing this one's metrics.
+    #[allow(clippy::should_implement_trait)]
     pub fn clone(&self) -> Result<Self, NodeStreamProducerError> {
         Ok(Self {
-            host_addr: self.host_addr,
-            kafka_producer: Producer::from_hosts(vec![self.host_addr.to_string()])
-                .create()
-                .map_err(|err| NodeStreamProducerError::UnableToCreateProducer { err })?,
+            backend: self.backend.clone(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+/// Publishes `records` to `topic`, sending again those not confirmed as
+/// published, after a retryable error, for as long as `policy` allows, along
+/// with the records after them sharing their key. Fails as a whole only if
+/// nothing could be published. Records confirmed before being sent again to
+/// keep their key in order, and those of a request failing as a whole which
+/// still wrote them, are duplicated.
+pub(crate) fn publish_with_retry<B: NodeStreamBackend>(
+    backend: &mut B,
+    policy: &RetryPolicy,
+    topic: &NodeStreamTopic,
+    records: Vec<NodeStreamRecord>,
+) -> Result<Vec<NodeStreamPublishResult>, NodeStreamBackendError> {
+    let mut results: Vec<Option<NodeStreamPublishResult>> = records.iter().map(|_| None).collect();
+    let mut pending = records.into_iter().enumerate().collect::<Vec<_>>();
+    let outcome = policy.run(PublishAttemptError::is_retryable, || {
+        let batch = pending.iter().map(|(_, record)| record.clone()).collect();
+        let published = backend
+            .publish_batch(topic, batch)
+            .map_err(PublishAttemptError::Request)?;
+        let mut failed = vec![];
+        // Keys a record was sent again for, whose later records follow it
+        let mut held_keys = HashSet::new();
+        for ((index, record), result) in pending.drain(..).zip(published) {
+            if held_keys.contains(&record.key)
+                || result.as_ref().is_err_and(|err| err.is_retryable())
+            {
+                held_keys.insert(record.key.clone());
+                failed.push((index, record));
+            }
+            results[index] = Some(result);
+        }
+        pending = failed;
+        match pending.len() {
+            0 => Ok(()),
+            failed => Err(PublishAttemptError::Records { failed }),
+        }
+    });
+    match outcome {
+        Err(PublishAttemptError::Request(err)) if results.iter().all(Option::is_none) => Err(err),
+        _ => Ok(results
+            .into_iter()
+            .map(|result| result.expect("every record was attempted"))
+            .collect()),
+    }
+}
+
+enum PublishAttemptError {
+    /// Nothing was published.
+    Request(NodeStreamBackendError),
+    /// Some records failed with retryable errors, and are to be sent again
+    /// along with the records after them sharing their key.
+    Records { failed: usize },
+}
+
+impl PublishAttemptError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(err) => err.is_retryable(),
+            Self::Records { .. } => true,
+        }
+    }
+}
+
+impl std::fmt::Display for PublishAttemptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Request(err) => write!(f, "{}", err),
+            Self::Records { failed } => write!(f, "{} records failed to publish", failed),
+        }
+    }
+}
+
+/// Receipt of a payload given the results of publishing its records: where
+/// its last record was written, unless one of them failed.
+pub(crate) fn payload_receipt(results: Vec<NodeStreamPublishResult>) -> NodeStreamPublishResult {
+    let mut receipt = None;
+    for result in results {
+        receipt = Some(result?);
+    }
+    Ok(receipt.expect("payloads have at least one record"))
+}
+
+/// Serializes `payload`, in an envelope unless the config asks for legacy
+/// records, into the records to publish for `epoch`: one, or its chunks if it
+/// is too large for one.
+pub(crate) fn encode_payload<
+    T: NodeStreamPerEpochTopic<D, M>,
+    D: std::fmt::Debug,
+    M: std::fmt::Debug,
+>(
+    config: &NodeStreamProducerConfig,
+    metrics: &NodeStreamProducerMetrics,
+    epoch: u64,
+    topic: &T,
+    payload: &NodeStreamUserPayload<D, M>,
+) -> Result<(NodeStreamTopic, Vec<NodeStreamRecord>), NodeStreamProducerError> {
+    let topic_name = topic.topic_for_epoch(epoch);
+    let key = topic.payload_key(payload);
+    let bytes = topic.payload_to_bytes(payload).map_err(|err| {
+        NodeStreamProducerError::PayloadSerializeError {
+            topic: topic_name.clone(),
+            err: format!("{:?}", err),
+        }
+    })?;
+    let bytes = if config.legacy_records {
+        if topic.compression().is_some() {
+            return Err(NodeStreamProducerError::UnableToCompress {
+                topic: topic_name,
+                err: "legacy records have no envelope to record the compression in".to_string(),
+            });
+        }
+        if config.encryption.is_some() {
+            return Err(NodeStreamProducerError::UnableToEncrypt {
+                topic: topic_name,
+                err: "legacy records have no envelope to record the key in".to_string(),
+            });
+        }
+        bytes
+    } else {
+        let mut header = NodeStreamEnvelopeHeader {
+            schema_version: topic.schema_version(),
+            codec: topic.codec_id(),
+            producer_id: config.producer_id.clone(),
+            produced_at_ms: envelope::now_ms(),
+            headers: BTreeMap::new(),
+        };
+        let bytes = match topic.compression() {
+            Some(compression) => {
+                let compressed = compression.compress(&mut header, &bytes).map_err(|err| {
+                    NodeStreamProducerError::UnableToCompress {
+                        topic: topic_name.clone(),
+                        err: err.to_string(),
+                    }
+                })?;
+                metrics.record_compression(bytes.len(), compressed.len());
+                compressed
+            }
+            None => bytes,
+        };
+        let bytes = match &config.encryption {
+            Some(keys) => keys
+                .encrypt(&mut header, epoch, &topic_name, &bytes)
+                .map_err(|err| NodeStreamProducerError::UnableToEncrypt {
+                    topic: topic_name.clone(),
+                    err,
+                })?,
+            None => bytes,
+        };
+        if let Some(algorithm) = config.checksum {
+            header.add_checksum(algorithm, &bytes);
+        }
+        if let Some(key) = &config.signing_key {
+            key.sign(&mut header, &topic_name, &bytes);
+        }
+        envelope::seal(&header, &bytes)
+    };
+    let size = bytes.len() as u64;
+    if size <= PAYLOAD_SIZE_LIMIT {
+        let record = NodeStreamRecord {
+            key,
+            value: bytes,
+            partition: None,
+        };
+        return Ok((topic_name, vec![record]));
+    }
+    let limit = match config.chunking && !config.legacy_records {
+        true => config.max_chunked_bytes,
+        false => PAYLOAD_SIZE_LIMIT,
+    };
+    if size > limit {
+        return Err(NodeStreamProducerError::PayloadTooLarge { limit, size });
+    }
+    let message_id: [u8; 16] = rand::random();
+    // Sharing a key keeps the chunks in order on one partition, so chunks of
+    // unkeyed payloads are keyed by their message id
+    let key = key
+        .filter(|key| !key.is_empty())
+        .unwrap_or_else(|| message_id.to_vec());
+    let chunks = chunking::split(&bytes, message_id, &config.producer_id)
+        .ok_or(NodeStreamProducerError::PayloadTooLarge {
+            limit: PAYLOAD_SIZE_LIMIT,
+            size,
+        })?
+        .into_iter()
+        .map(|value| NodeStreamRecord {
+            key: Some(key.clone()),
+            value,
+            partition: None,
         })
+        .collect();
+    Ok((topic_name, chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryBroker;
+    use crate::test_utils::{
+        self, payload, read_all, unconfirmed, FaultyBackend, PublishFault, TestTopic,
+    };
+    use std::time::Duration;
+
+    fn topic() -> TestTopic {
+        TestTopic::new("producer-test-")
+    }
+
+    fn producer(backend: &FaultyBackend) -> NodeStreamProducer<FaultyBackend> {
+        let config = NodeStreamProducerConfig {
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        NodeStreamProducer::with_config(backend.clone(), config)
+    }
+
+    #[test]
+    fn clones_publish_to_the_same_backend() {
+        let broker = InMemoryBroker::new();
+        let mut producer = NodeStreamProducer::with_backend(broker.backend());
+        let mut clone = producer.clone().unwrap();
+        producer.send(0, topic(), &payload(0)).unwrap();
+        clone.send(0, topic(), &payload(1)).unwrap();
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0, 1]);
+    }
+
+    #[test]
+    fn retries_keep_the_order_of_each_key() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        backend.fail_next(PublishFault::Records(BTreeMap::from([(
+            1,
+            unconfirmed(&topic(), 0),
+        )])));
+        backend.fail_next(PublishFault::Request {
+            err: unconfirmed(&topic(), 0),
+            published: false,
+        });
+        let topic = topic().with_key(|data, _| vec![(data % 2) as u8]);
+        let payloads = (0..4).map(payload).collect::<Vec<_>>();
+        let results = producer(&backend)
+            .send_batch(0, topic.clone(), &payloads)
+            .unwrap();
+        assert!(results.iter().all(Result::is_ok));
+        // 3 shares the key of the failed 1, so it is sent again after it, 2 is not
+        assert_eq!(backend.batches(), vec![4, 2, 2]);
+        assert_eq!(read_all(&broker, &topic, 0), vec![0, 2, 3, 1, 3]);
+    }
+
+    #[test]
+    fn requests_failing_after_publishing_are_delivered_twice() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        backend.fail_next(PublishFault::Request {
+            err: unconfirmed(&topic(), 0),
+            published: true,
+        });
+        producer(&backend).send(0, topic(), &payload(0)).unwrap();
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0, 0]);
+    }
+
+    #[test]
+    fn receipts_tell_where_payloads_landed() {
+        let broker = InMemoryBroker::new();
+        let mut producer = producer(&FaultyBackend::new(&broker));
+        let mut chunked = payload(1);
+        chunked.metdata = "x".repeat(2 * PAYLOAD_SIZE_LIMIT as usize);
+        let receipts = [payload(0), chunked, payload(2)]
+            .iter()
+            .map(|payload| producer.send(0, topic(), payload).unwrap())
+            .collect::<Vec<_>>();
+
+        let messages = test_utils::consumer(&broker, None, &topic(), 0, Default::default())
+            .poll()
+            .unwrap();
+        assert_eq!(messages.len(), 3);
+        for (receipt, message) in receipts.iter().zip(&messages) {
+            assert_eq!(receipt.topic, topic().topic_for_epoch(0));
+            assert_eq!(receipt.partition, 0);
+            assert_eq!(receipt.offset, message.message_offset);
+        }
+        // The chunked payload is reported at its last chunk
+        assert!(receipts[1].offset > 2);
+        assert_eq!(receipts[2].offset, receipts[1].offset + 1);
+        let len = broker.topic_len(&topic().topic_for_epoch(0)).unwrap();
+        assert_eq!(receipts[2].offset + 1, len as i64);
+    }
+
+    #[test]
+    fn batch_results_line_up_with_their_payloads() {
+        let broker = InMemoryBroker::new();
+        let backend = FaultyBackend::new(&broker);
+        let mut payloads = (0..5).map(payload).collect::<Vec<_>>();
+        payloads[1].metdata = "x".repeat(PAYLOAD_SIZE_LIMIT as usize);
+        // Payload 1 is never published, so payload 3 has the third record
+        backend.fail_next(PublishFault::Records(BTreeMap::from([(
+            2,
+            NodeStreamBackendError::NotSubscribed,
+        )])));
+
+        let config = NodeStreamProducerConfig {
+            chunking: false,
+            ..Default::default()
+        };
+        let results = NodeStreamProducer::with_config(backend, config)
+            .send_batch(0, topic(), &payloads)
+            .unwrap();
+        assert_eq!(results.len(), 5);
+        assert!(matches!(
+            results[1],
+            Err(NodeStreamProducerError::PayloadTooLarge { .. })
+        ));
+        assert!(matches!(
+            results[3],
+            Err(NodeStreamProducerError::MessageSendFailed {
+                err: NodeStreamBackendError::NotSubscribed
+            })
+        ));
+        let offsets = [0, 2, 4].map(|i| results[i].as_ref().unwrap().offset);
+        assert_eq!(offsets, [0, 1, 2]);
+        assert_eq!(read_all(&broker, &topic(), 0), vec![0, 2, 4]);
     }
 }
@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+/// How often and how fast a failed operation is tried again. Only errors
+/// classified as retryable are retried, see `is_retryable` on the error types.
+///
+/// The delay before retry `n` is `base_delay * 2^(n - 1)`, capped at
+/// `max_delay` and then reduced by up to `jitter` of itself at random, so
+/// clients failing together do not retry together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one. 1 disables retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay, between 0 and 1, randomly taken off it.
+    pub jitter: f64,
+    /// Time after the first attempt past which no retry is started.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: 0.2,
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Tries once, never retrying.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before the `retry`th retry, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        exponential.min(self.max_delay).mul_f64(1.0 - jitter)
+    }
+
+    /// Runs `op` until it succeeds, fails with an error `is_retryable` rejects,
+    /// or the policy gives up, in which case the last error is returned.
+    /// Sleeps the calling thread between attempts.
+    pub fn run<T, E: std::fmt::Display>(
+        &self,
+        is_retryable: impl Fn(&E) -> bool,
+        mut op: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let err = match op() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if attempt >= self.max_attempts || !is_retryable(&err) {
+                return Err(err);
+            }
+            let delay = self.delay(attempt);
+            if self
+                .deadline
+                .is_some_and(|deadline| start.elapsed() + delay > deadline)
+            {
+                return Err(err);
+            }
+            tracing::debug!(
+                "attempt {} of {} failed, retrying in {:?}, err: {}",
+                attempt,
+                self.max_attempts,
+                delay,
+                err
+            );
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(3),
+            jitter: 0.0,
+            deadline: None,
+        }
+    }
+
+    /// Runs `policy` on an op failing with `retryable` every time, returning
+    /// the number of attempts.
+    fn attempts(policy: &RetryPolicy, retryable: bool) -> u32 {
+        let attempts = Cell::new(0);
+        let result: Result<(), String> = policy.run(
+            |_| retryable,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(format!("attempt {}", attempts.get()))
+            },
+        );
+        assert_eq!(result, Err(format!("attempt {}", attempts.get())));
+        attempts.get()
+    }
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            ..policy()
+        };
+        let delays = (1..=6).map(|retry| policy.delay(retry)).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_only_shortens_delays_by_up_to_its_fraction() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.25,
+            ..policy()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay <= Duration::from_millis(1000), "{:?}", delay);
+            assert!(delay >= Duration::from_millis(750), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn retries_stop_after_max_attempts() {
+        assert_eq!(attempts(&policy(), true), 4);
+        assert_eq!(attempts(&RetryPolicy::none(), true), 1);
+
+        // Success ends the retries
+        let failures = Cell::new(2u32);
+        let result = policy().run(
+            |_: &String| true,
+            || match failures.replace(failures.get().saturating_sub(1)) {
+                0 => Ok("done"),
+                _ => Err("failed".to_string()),
+            },
+        );
+        assert_eq!(result, Ok("done"));
+    }
+
+    #[test]
+    fn deadline_cuts_retries_short() {
+        let policy = RetryPolicy {
+            max_attempts: 100,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(100),
+            deadline: Some(Duration::from_millis(250)),
+            ..policy()
+        };
+        // Retries at 100ms and 200ms, the next one would start past 250ms
+        assert_eq!(attempts(&policy, true), 3);
+    }
+
+    #[test]
+    fn non_retryable_errors_are_returned_at_once() {
+        assert_eq!(attempts(&policy(), false), 1);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::envelope::NodeStreamEnvelopeHeader;
+use crate::types::NodeStreamTopic;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use sui_types::crypto::{
+    AuthorityKeyPair, AuthorityPublicKey, AuthorityPublicKeyBytes, AuthoritySignature,
+    KeypairTraits, Signer, ToFromBytes, VerifyingKey,
+};
+
+/// Envelope header holding the signer's public key and the signature.
+pub const SIGNATURE_HEADER: &str = "signature.authority";
+
+/// Separates envelope signatures from anything else signed with the same key.
+const SIGNING_DOMAIN: &str = "node-stream/envelope";
+
+/// Authority key producers sign payloads with.
+#[derive(Clone)]
+pub struct NodeStreamSigningKey {
+    key: Arc<AuthorityKeyPair>,
+}
+
+impl NodeStreamSigningKey {
+    pub fn new(key: AuthorityKeyPair) -> Self {
+        Self { key: Arc::new(key) }
+    }
+
+    pub fn public(&self) -> AuthorityPublicKeyBytes {
+        self.key.public().into()
+    }
+
+    /// Signs `payload` along with every field of `header`, as published to
+    /// `topic` so the signature does not hold for the same payload replayed
+    /// onto another topic. Headers added to `header` afterwards make the
+    /// signature invalid, so this has to come last.
+    pub(crate) fn sign(
+        &self,
+        header: &mut NodeStreamEnvelopeHeader,
+        topic: &NodeStreamTopic,
+        payload: &[u8],
+    ) {
+        let signature: AuthoritySignature = self.key.sign(&signing_message(topic, header, payload));
+        let value = bcs::to_bytes(&(self.public(), signature.as_bytes()))
+            .expect("signatures are always serializable");
+        header.headers.insert(SIGNATURE_HEADER.to_string(), value);
+    }
+}
+
+impl std::fmt::Debug for NodeStreamSigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeStreamSigningKey")
+            .field("public", &self.public())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStreamSignatureCheck {
+    /// Signatures are not looked at.
+    Disabled,
+    /// Signatures are verified and the outcome is set on each message.
+    Flag,
+    /// Only messages with a valid signature from a trusted signer are
+    /// returned, others fail with `InvalidSignature`.
+    Require,
+}
+
+/// Outcome of verifying a message's signature.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeStreamSignatureStatus {
+    #[default]
+    NotChecked,
+    Unsigned,
+    /// The signature is malformed or does not match the payload.
+    Invalid,
+    /// The signature is valid, but from a key which is not trusted.
+    Untrusted {
+        signer: AuthorityPublicKeyBytes,
+    },
+    Verified {
+        signer: AuthorityPublicKeyBytes,
+    },
+}
+
+/// Verifies the signature of `payload`, read from `topic`, against `trusted` signers.
+pub(crate) fn verify(
+    header: Option<&NodeStreamEnvelopeHeader>,
+    topic: &NodeStreamTopic,
+    payload: &[u8],
+    trusted: &[AuthorityPublicKeyBytes],
+) -> NodeStreamSignatureStatus {
+    let (header, value) = match header.and_then(|h| Some((h, h.headers.get(SIGNATURE_HEADER)?))) {
+        Some(signed) => signed,
+        None => return NodeStreamSignatureStatus::Unsigned,
+    };
+    let (signer, signature) = match bcs::from_bytes::<(AuthorityPublicKeyBytes, Vec<u8>)>(value) {
+        Ok(decoded) => decoded,
+        Err(_) => return NodeStreamSignatureStatus::Invalid,
+    };
+    let valid = AuthorityPublicKey::try_from(signer)
+        .ok()
+        .zip(AuthoritySignature::from_bytes(&signature).ok())
+        .is_some_and(|(key, signature)| {
+            key.verify(&signing_message(topic, header, payload), &signature)
+                .is_ok()
+        });
+    if !valid {
+        NodeStreamSignatureStatus::Invalid
+    } else if trusted.contains(&signer) {
+        NodeStreamSignatureStatus::Verified { signer }
+    } else {
+        NodeStreamSignatureStatus::Untrusted { signer }
+    }
+}
+
+/// What is signed: the topic, the header without the signature, and the payload.
+fn signing_message(
+    topic: &NodeStreamTopic,
+    header: &NodeStreamEnvelopeHeader,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut header = header.clone();
+    header.headers.remove(SIGNATURE_HEADER);
+    bcs::to_bytes(&(SIGNING_DOMAIN, &topic.topic, &header, payload))
+        .expect("messages are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::NodeStreamConsumerConfig;
+    use crate::memory::InMemoryBroker;
+    use crate::producer::{NodeStreamProducer, NodeStreamProducerConfig};
+    use crate::test_utils::{consumer, header, payload, poll_err, TestTopic};
+    use crate::types::NodeStreamConsumerError;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn signing_key(seed: u8) -> NodeStreamSigningKey {
+        NodeStreamSigningKey::new(AuthorityKeyPair::generate(&mut StdRng::from_seed(
+            [seed; 32],
+        )))
+    }
+
+    fn topic(name: &str) -> NodeStreamTopic {
+        NodeStreamTopic::new(name.to_string())
+    }
+
+    #[test]
+    fn signed_records_verify() {
+        let key = signing_key(1);
+        let mut signed = header();
+        key.sign(&mut signed, &topic("signed"), b"payload");
+        assert_eq!(
+            verify(Some(&signed), &topic("signed"), b"payload", &[key.public()]),
+            NodeStreamSignatureStatus::Verified {
+                signer: key.public()
+            }
+        );
+        assert_eq!(
+            verify(Some(&signed), &topic("signed"), b"payload", &[]),
+            NodeStreamSignatureStatus::Untrusted {
+                signer: key.public()
+            }
+        );
+        assert_eq!(
+            verify(Some(&header()), &topic("signed"), b"payload", &[]),
+            NodeStreamSignatureStatus::Unsigned
+        );
+        assert_eq!(
+            verify(None, &topic("signed"), b"payload", &[]),
+            NodeStreamSignatureStatus::Unsigned
+        );
+    }
+
+    #[test]
+    fn tampered_records_are_invalid() {
+        let key = signing_key(1);
+        let trusted = [key.public()];
+        let mut signed = header();
+        key.sign(&mut signed, &topic("signed"), b"payload");
+
+        let mut producer = signed.clone();
+        producer.producer_id = "someone else".to_string();
+        let mut extra_header = signed.clone();
+        extra_header
+            .headers
+            .insert("checksum.crc32c".to_string(), vec![0; 4]);
+        let mut garbled = signed.clone();
+        garbled
+            .headers
+            .insert(SIGNATURE_HEADER.to_string(), vec![1, 2, 3]);
+        for (header, topic, payload) in [
+            (&signed, topic("signed"), b"PAYLOAD".as_slice()),
+            (&signed, topic("other"), b"payload".as_slice()),
+            (&producer, topic("signed"), b"payload".as_slice()),
+            (&extra_header, topic("signed"), b"payload".as_slice()),
+            (&garbled, topic("signed"), b"payload".as_slice()),
+        ] {
+            assert_eq!(
+                verify(Some(header), &topic, payload, &trusted),
+                NodeStreamSignatureStatus::Invalid
+            );
+        }
+    }
+
+    #[test]
+    fn required_signatures_reject_unsigned_and_legacy_records() {
+        let topic = TestTopic::new("signature-test-");
+        let key = signing_key(1);
+        let broker = InMemoryBroker::new();
+        for producer_config in [
+            NodeStreamProducerConfig::default(),
+            NodeStreamProducerConfig {
+                legacy_records: true,
+                ..Default::default()
+            },
+            NodeStreamProducerConfig {
+                signing_key: Some(signing_key(2)),
+                ..Default::default()
+            },
+            NodeStreamProducerConfig {
+                signing_key: Some(key.clone()),
+                ..Default::default()
+            },
+        ] {
+            let mut producer = NodeStreamProducer::with_config(broker.backend(), producer_config);
+            producer.send(0, topic.clone(), &payload(0)).unwrap();
+        }
+
+        let config = NodeStreamConsumerConfig {
+            signature_check: NodeStreamSignatureCheck::Require,
+            trusted_signers: vec![key.public()],
+            ..Default::default()
+        };
+        let mut consumer = consumer(&broker, None, &topic, 0, config);
+        let untrusted = NodeStreamSignatureStatus::Untrusted {
+            signer: signing_key(2).public(),
+        };
+        for (offset, status) in [
+            (0, NodeStreamSignatureStatus::Unsigned),
+            (1, NodeStreamSignatureStatus::Unsigned),
+            (2, untrusted),
+        ] {
+            match poll_err(&mut consumer, offset) {
+                NodeStreamConsumerError::InvalidSignature {
+                    offset: failed,
+                    status: failed_status,
+                    ..
+                } => assert_eq!((failed, failed_status), (offset, status)),
+                other => panic!("{:?}", other),
+            }
+        }
+        let messages = consumer.poll().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].signature,
+            NodeStreamSignatureStatus::Verified {
+                signer: key.public()
+            }
+        );
+    }
+}
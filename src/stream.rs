@@ -0,0 +1,298 @@
+use crate::backend::NodeStreamBackend;
+use crate::consumer::NodeStreamConsumer;
+use crate::types::{NodeStreamConsumerError, NodeStreamMessage, NodeStreamPerEpochTopic};
+use futures::Stream;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Polls buffered between the polling worker and the stream, on top of the
+/// one the stream is yielding messages from.
+const STREAM_BUFFER: usize = 1;
+
+type StreamItem<D, M> = Result<NodeStreamMessage<D, M>, NodeStreamConsumerError>;
+type PollResult<D, M> = Result<Vec<NodeStreamMessage<D, M>>, NodeStreamConsumerError>;
+
+/// Async stream over a `NodeStreamConsumer`, created by
+/// `NodeStreamConsumer::into_stream`.
+///
+/// The consumer is polled on a blocking worker which stops once the stream is
+/// dropped or `into_inner` is called. The worker reads at most a poll or two
+/// ahead of the stream. In auto commit mode only the messages the stream
+/// yielded are committed, so those read ahead are read again after a
+/// restart, or returned by the next poll of the consumer handed back by
+/// `into_inner`.
+///
+/// Retryable errors are yielded and polling carries on after backing off
+/// according to the consumer's retry policy. Polls returning nothing are
+/// followed by the policy's first retry delay. Any other error, such as a
+/// message `NodeStreamPoisonPolicy::Fail` does not get past, is yielded once
+/// and ends the stream. The stream also ends when an epoch range replay
+/// finishes.
+pub struct NodeStreamConsumerStream<T, D, M, B>
+where
+    T: NodeStreamPerEpochTopic<D, M>,
+    D: std::fmt::Debug,
+    M: std::fmt::Debug,
+    B: NodeStreamBackend,
+{
+    receiver: mpsc::Receiver<PollResult<D, M>>,
+    worker: JoinHandle<NodeStreamConsumer<T, D, M, B>>,
+    /// Messages of the last poll not yielded yet.
+    buffer: VecDeque<NodeStreamMessage<D, M>>,
+    /// Whether the stream acks messages as it yields them, for a consumer in
+    /// auto commit mode.
+    auto_commit: bool,
+}
+
+// Nothing in the stream is pinned
+impl<T, D, M, B> Unpin for NodeStreamConsumerStream<T, D, M, B>
+where
+    T: NodeStreamPerEpochTopic<D, M>,
+    D: std::fmt::Debug,
+    M: std::fmt::Debug,
+    B: NodeStreamBackend,
+{
+}
+
+impl<T, D, M, B> NodeStreamConsumerStream<T, D, M, B>
+where
+    T: NodeStreamPerEpochTopic<D, M> + std::fmt::Debug + Send + 'static,
+    D: std::fmt::Debug + Send + 'static,
+    M: std::fmt::Debug + Send + 'static,
+    B: NodeStreamBackend + 'static,
+{
+    pub(crate) fn new(mut consumer: NodeStreamConsumer<T, D, M, B>) -> Self {
+        let auto_commit = consumer.start_streaming();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let worker = tokio::task::spawn_blocking(move || {
+            let mut failures = 0;
+            while !sender.is_closed() && !consumer.is_finished() {
+                let result = consumer.poll();
+                let retry = match &result {
+                    Ok(messages) if messages.is_empty() => {
+                        // Backends returning at once when there is nothing to
+                        // read would otherwise be polled in a busy loop
+                        if !consumer.is_finished() {
+                            std::thread::sleep(consumer.retry_policy().delay(1));
+                        }
+                        continue;
+                    }
+                    Ok(_) => false,
+                    Err(err) => err.is_retryable(),
+                };
+                let fatal = result.is_err() && !retry;
+                if let Err(mpsc::error::SendError(result)) = sender.blocking_send(result) {
+                    if let Ok(messages) = result {
+                        consumer.unread(messages.into_iter());
+                    }
+                    break;
+                }
+                if fatal {
+                    break;
+                }
+                if retry {
+                    failures += 1;
+                    std::thread::sleep(consumer.retry_policy().delay(failures));
+                } else {
+                    failures = 0;
+                }
+            }
+            consumer.stop_streaming(auto_commit);
+            consumer
+        });
+        Self {
+            receiver,
+            worker,
+            buffer: VecDeque::new(),
+            auto_commit,
+        }
+    }
+
+    /// Stops polling and hands back the consumer once the worker has finished
+    /// its current poll. Messages read but not yielded by the stream are
+    /// returned by the consumer's next poll.
+    pub async fn into_inner(self) -> Option<NodeStreamConsumer<T, D, M, B>> {
+        let Self {
+            mut receiver,
+            worker,
+            mut buffer,
+            ..
+        } = self;
+        receiver.close();
+        // Unblocks a worker waiting for room in the buffer
+        while let Some(result) = receiver.recv().await {
+            buffer.extend(result.into_iter().flatten());
+        }
+        let mut consumer = worker.await.ok()?;
+        consumer.unread(buffer.into_iter());
+        Some(consumer)
+    }
+}
+
+impl<T, D, M, B> Stream for NodeStreamConsumerStream<T, D, M, B>
+where
+    T: NodeStreamPerEpochTopic<D, M>,
+    D: std::fmt::Debug,
+    M: std::fmt::Debug,
+    B: NodeStreamBackend,
+{
+    type Item = StreamItem<D, M>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(message) = self.buffer.pop_front() {
+                if !self.auto_commit {
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                // Committed by the worker's next poll
+                message.ack();
+                return Poll::Ready(Some(Ok(NodeStreamMessage {
+                    ack: None,
+                    ..message
+                })));
+            }
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(messages))) => self.buffer.extend(messages),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consumer::{NodeStreamCommitMode, NodeStreamConsumerConfig};
+    use crate::memory::InMemoryBroker;
+    use crate::test_utils::{self, publish, publish_raw, TestConsumer, TestTopic};
+    use crate::types::{NodeStreamConsumerError, NodeStreamSessionId};
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn topic() -> TestTopic {
+        TestTopic::new("stream-test-")
+    }
+
+    fn consumer(
+        broker: &InMemoryBroker,
+        session: NodeStreamSessionId,
+        commit_mode: NodeStreamCommitMode,
+    ) -> TestConsumer {
+        let config = NodeStreamConsumerConfig {
+            commit_mode,
+            ..Default::default()
+        };
+        test_utils::consumer(broker, Some(session), &topic(), 0, config)
+    }
+
+    /// Offsets returned by the next poll of a new consumer of `session`,
+    /// without committing anything.
+    fn uncommitted(broker: &InMemoryBroker, session: NodeStreamSessionId) -> Vec<i64> {
+        consumer(broker, session, NodeStreamCommitMode::ExplicitAck)
+            .poll()
+            .unwrap()
+            .iter()
+            .map(|m| m.message_offset)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn auto_commit_only_commits_yielded_messages() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..5);
+        let session = NodeStreamSessionId::new();
+        let mut stream = consumer(&broker, session, NodeStreamCommitMode::Auto).into_stream();
+        for data in 0..2 {
+            let message = stream.next().await.unwrap().unwrap();
+            assert_eq!(message.payload.data, data);
+            assert!(message.ack.is_none());
+        }
+
+        let mut consumer = stream.into_inner().await.unwrap();
+        assert_eq!(uncommitted(&broker, session), vec![2, 3, 4]);
+        // The messages read ahead are handed back, and committed once returned
+        let messages = consumer.poll().unwrap();
+        assert_eq!(
+            messages.iter().map(|m| m.payload.data).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert!(messages.iter().all(|m| m.ack.is_none()));
+        assert_eq!(uncommitted(&broker, session), Vec::<i64>::new());
+    }
+
+    #[tokio::test]
+    async fn dropped_streams_only_commit_yielded_messages() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..5);
+        let session = NodeStreamSessionId::new();
+        let mut stream = consumer(&broker, session, NodeStreamCommitMode::Auto).into_stream();
+        for _ in 0..2 {
+            stream.next().await.unwrap().unwrap();
+        }
+        drop(stream);
+
+        // The worker commits what was yielded once it notices the drop
+        for _ in 0..50 {
+            if uncommitted(&broker, session) == vec![2, 3, 4] {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("read {:?}", uncommitted(&broker, session));
+    }
+
+    #[tokio::test]
+    async fn explicit_acks_are_committed_and_unread_messages_kept() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..5);
+        let session = NodeStreamSessionId::new();
+        let mut stream =
+            consumer(&broker, session, NodeStreamCommitMode::ExplicitAck).into_stream();
+        for data in 0..3 {
+            let message = stream.next().await.unwrap().unwrap();
+            if data < 2 {
+                message.ack();
+            }
+        }
+
+        let mut consumer = stream.into_inner().await.unwrap();
+        assert_eq!(uncommitted(&broker, session), vec![2, 3, 4]);
+        let messages = consumer.poll().unwrap();
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| m.message_offset)
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert!(messages.iter().all(|m| m.ack.is_some()));
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_end_the_stream() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &topic(), 0, 0..2);
+        publish_raw(&broker, &topic(), 0, vec![0xff; 3]);
+        publish(&broker, &topic(), 0, 3..5);
+        let session = NodeStreamSessionId::new();
+        let mut stream = consumer(&broker, session, NodeStreamCommitMode::Auto).into_stream();
+
+        for data in 0..2 {
+            assert_eq!(stream.next().await.unwrap().unwrap().payload.data, data);
+        }
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(NodeStreamConsumerError::PayloadDeserializeError { .. }))
+        ));
+        assert!(stream.next().await.is_none());
+        // The consumer still stops at the message
+        let mut consumer = stream.into_inner().await.unwrap();
+        assert!(consumer.poll().is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! Fixtures shared by the tests of several modules, all on an `InMemoryBroker`.
+
+use crate::backend::{
+    NodeStreamBackend, NodeStreamDeliveryReceipt, NodeStreamFetchedRecord,
+    NodeStreamPartitionMetadata, NodeStreamPartitionOffset, NodeStreamPublishResult,
+    NodeStreamRecord, NodeStreamStartPosition, NodeStreamTopicConfig, NodeStreamTopicMetadata,
+};
+use crate::codec::NodeStreamCodecId;
+use crate::consumer::{NodeStreamConsumer, NodeStreamConsumerConfig};
+use crate::envelope::NodeStreamEnvelopeHeader;
+use crate::memory::{InMemoryBackend, InMemoryBroker};
+use crate::producer::NodeStreamProducer;
+use crate::topics::BcsTopic;
+use crate::types::{
+    NodeStreamBackendError, NodeStreamConsumerError, NodeStreamPerEpochTopic, NodeStreamSessionId,
+    NodeStreamTopic, NodeStreamUserPayload,
+};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+pub(crate) type TestTopic = BcsTopic<u64, String>;
+pub(crate) type TestConsumer = NodeStreamConsumer<TestTopic, u64, String, InMemoryBackend>;
+
+/// Header of an envelope without any extra header.
+pub(crate) fn header() -> NodeStreamEnvelopeHeader {
+    NodeStreamEnvelopeHeader {
+        schema_version: 0,
+        codec: NodeStreamCodecId::BCS,
+        producer_id: "producer".to_string(),
+        produced_at_ms: 1,
+        headers: BTreeMap::new(),
+    }
+}
+
+pub(crate) fn payload(data: u64) -> NodeStreamUserPayload<u64, String> {
+    NodeStreamUserPayload {
+        metdata: format!("message {}", data),
+        data,
+    }
+}
+
+/// Sends a payload for each of `data` to `epoch` of `topic`.
+pub(crate) fn publish(broker: &InMemoryBroker, topic: &TestTopic, epoch: u64, data: Range<u64>) {
+    let mut producer = NodeStreamProducer::with_backend(broker.backend());
+    for data in data {
+        producer.send(epoch, topic.clone(), &payload(data)).unwrap();
+    }
+}
+
+/// Publishes `value` to `epoch` of `topic` as is, without an envelope.
+pub(crate) fn publish_raw(broker: &InMemoryBroker, topic: &TestTopic, epoch: u64, value: Vec<u8>) {
+    broker
+        .backend()
+        .publish(
+            &topic.topic_for_epoch(epoch),
+            NodeStreamRecord {
+                key: None,
+                value,
+                partition: None,
+            },
+        )
+        .unwrap();
+}
+
+pub(crate) fn consumer(
+    broker: &InMemoryBroker,
+    session: Option<NodeStreamSessionId>,
+    topic: &TestTopic,
+    epoch: u64,
+    config: NodeStreamConsumerConfig,
+) -> TestConsumer {
+    NodeStreamConsumer::with_config(broker.backend(), session, epoch, topic.clone(), config)
+        .unwrap()
+}
+
+/// Data of every message of `epoch` of `topic`, read by a new group.
+pub(crate) fn read_all(broker: &InMemoryBroker, topic: &TestTopic, epoch: u64) -> Vec<u64> {
+    consumer(broker, None, topic, epoch, Default::default())
+        .poll()
+        .unwrap()
+        .iter()
+        .map(|m| m.payload.data)
+        .collect()
+}
+
+/// Polls the error the record at `offset` fails with, then seeks past it.
+pub(crate) fn poll_err(consumer: &mut TestConsumer, offset: i64) -> NodeStreamConsumerError {
+    let err = consumer.poll().unwrap_err();
+    consumer
+        .seek(NodeStreamStartPosition::Offsets([(0, offset + 1)].into()))
+        .unwrap();
+    err
+}
+
+/// How `FaultyBackend` fails a `publish_batch` call.
+pub(crate) enum PublishFault {
+    /// Fails the request as a whole. With `published` set its records were
+    /// written anyway, like a request whose response got lost.
+    Request {
+        err: NodeStreamBackendError,
+        published: bool,
+    },
+    /// Fails the records at these indices of the batch, publishing the rest.
+    Records(BTreeMap<usize, NodeStreamBackendError>),
+}
+
+/// `InMemoryBackend` failing the next publishes with the faults queued up,
+/// one per `publish_batch` call, and keeping the size of every batch. Clones
+/// share both.
+#[derive(Clone)]
+pub(crate) struct FaultyBackend {
+    inner: InMemoryBackend,
+    faults: Arc<Mutex<VecDeque<PublishFault>>>,
+    batches: Arc<Mutex<Vec<usize>>>,
+}
+
+impl FaultyBackend {
+    pub(crate) fn new(broker: &InMemoryBroker) -> Self {
+        Self {
+            inner: broker.backend(),
+            faults: Arc::default(),
+            batches: Arc::default(),
+        }
+    }
+
+    /// Number of records of each `publish_batch` call so far.
+    pub(crate) fn batches(&self) -> Vec<usize> {
+        self.batches.lock().unwrap().clone()
+    }
+
+    pub(crate) fn fail_next(&self, fault: PublishFault) {
+        self.faults.lock().unwrap().push_back(fault);
+    }
+}
+
+/// A retryable error.
+pub(crate) fn unconfirmed(topic: &TestTopic, epoch: u64) -> NodeStreamBackendError {
+    NodeStreamBackendError::UnconfirmedDelivery {
+        topic: topic.topic_for_epoch(epoch),
+        partition: 0,
+    }
+}
+
+impl NodeStreamBackend for FaultyBackend {
+    fn publish(
+        &mut self,
+        topic: &NodeStreamTopic,
+        record: NodeStreamRecord,
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamBackendError> {
+        self.publish_batch(topic, vec![record])?
+            .pop()
+            .expect("one result per record")
+    }
+
+    fn publish_batch(
+        &mut self,
+        topic: &NodeStreamTopic,
+        records: Vec<NodeStreamRecord>,
+    ) -> Result<Vec<NodeStreamPublishResult>, NodeStreamBackendError> {
+        self.batches.lock().unwrap().push(records.len());
+        let fault = self.faults.lock().unwrap().pop_front();
+        match fault {
+            None => self.inner.publish_batch(topic, records),
+            Some(PublishFault::Request { err, published }) => {
+                if published {
+                    self.inner.publish_batch(topic, records)?;
+                }
+                Err(err)
+            }
+            Some(PublishFault::Records(mut failed)) => Ok(records
+                .into_iter()
+                .enumerate()
+                .map(|(index, record)| match failed.remove(&index) {
+                    Some(err) => Err(err),
+                    None => self.inner.publish(topic, record),
+                })
+                .collect()),
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        start: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        self.inner.subscribe(topic, group_id, start)
+    }
+
+    fn seek(
+        &mut self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        self.inner.seek(topic, position)
+    }
+
+    fn positions(&mut self) -> Result<Vec<NodeStreamPartitionOffset>, NodeStreamBackendError> {
+        self.inner.positions()
+    }
+
+    fn poll(&mut self) -> Result<Vec<NodeStreamFetchedRecord>, NodeStreamBackendError> {
+        self.inner.poll()
+    }
+
+    fn commit(
+        &mut self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) -> Result<(), NodeStreamBackendError> {
+        self.inner.commit(topic, offsets)
+    }
+
+    fn topic_metadata(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamBackendError> {
+        self.inner.topic_metadata(topic)
+    }
+
+    fn create_topic(
+        &mut self,
+        topic: &NodeStreamTopic,
+        config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamBackendError> {
+        self.inner.create_topic(topic, config)
+    }
+
+    fn delete_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        self.inner.delete_topic(topic)
+    }
+
+    fn list_topics(&mut self) -> Result<Vec<NodeStreamTopic>, NodeStreamBackendError> {
+        self.inner.list_topics()
+    }
+}
+
+/// Backend whose topics have several partitions, each kept as a topic of its
+/// own on an `InMemoryBroker`. Records without a partition go round robin.
+/// Each poll reads from the next partition in turn, short of those held back
+/// with `hold`. Clones share what is held back.
+#[derive(Clone)]
+pub(crate) struct PartitionedBackend {
+    partitions: Vec<InMemoryBackend>,
+    subscription: Option<NodeStreamTopic>,
+    next_publish: usize,
+    next_poll: usize,
+    held: Arc<Mutex<BTreeSet<i32>>>,
+}
+
+impl PartitionedBackend {
+    pub(crate) fn new(broker: &InMemoryBroker, partitions: usize) -> Self {
+        Self {
+            partitions: (0..partitions).map(|_| broker.backend()).collect(),
+            subscription: None,
+            next_publish: 0,
+            next_poll: 0,
+            held: Arc::default(),
+        }
+    }
+
+    /// Leaves `partition` out of polls, like a fetch lagging behind on it,
+    /// until it is released.
+    pub(crate) fn hold(&self, partition: i32) {
+        self.held.lock().unwrap().insert(partition);
+    }
+
+    pub(crate) fn release(&self, partition: i32) {
+        self.held.lock().unwrap().remove(&partition);
+    }
+
+    fn partition(
+        &mut self,
+        partition: i32,
+    ) -> Result<&mut InMemoryBackend, NodeStreamBackendError> {
+        self.partitions.get_mut(partition as usize).ok_or_else(|| {
+            NodeStreamBackendError::Unsupported {
+                operation: format!("publishing to partition {}", partition),
+            }
+        })
+    }
+}
+
+/// In-memory topic holding `partition` of `topic`.
+fn partition_topic(topic: &NodeStreamTopic, partition: i32) -> NodeStreamTopic {
+    NodeStreamTopic::new(format!("{}.{}", topic.to_raw(), partition))
+}
+
+/// `position` for the in-memory topic holding `partition`.
+fn partition_position(
+    position: &NodeStreamStartPosition,
+    partition: i32,
+) -> NodeStreamStartPosition {
+    match position {
+        NodeStreamStartPosition::Offsets(offsets) => NodeStreamStartPosition::Offsets(
+            offsets
+                .get(&partition)
+                .map(|offset| (0, *offset))
+                .into_iter()
+                .collect(),
+        ),
+        position => position.clone(),
+    }
+}
+
+impl NodeStreamBackend for PartitionedBackend {
+    fn publish(
+        &mut self,
+        topic: &NodeStreamTopic,
+        record: NodeStreamRecord,
+    ) -> Result<NodeStreamDeliveryReceipt, NodeStreamBackendError> {
+        // Every partition exists once the topic does
+        self.create_topic(topic, &Default::default())?;
+        let partition = record.partition.unwrap_or_else(|| {
+            let partition = self.next_publish % self.partitions.len();
+            self.next_publish = partition + 1;
+            partition as i32
+        });
+        let receipt = self.partition(partition)?.publish(
+            &partition_topic(topic, partition),
+            NodeStreamRecord {
+                partition: None,
+                ..record
+            },
+        )?;
+        Ok(NodeStreamDeliveryReceipt {
+            topic: topic.clone(),
+            partition,
+            offset: receipt.offset,
+        })
+    }
+
+    fn subscribe(
+        &mut self,
+        topic: &NodeStreamTopic,
+        group_id: &str,
+        start: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        for (partition, backend) in (0..).zip(&mut self.partitions) {
+            backend.subscribe(
+                &partition_topic(topic, partition),
+                group_id,
+                &partition_position(start, partition),
+            )?;
+        }
+        self.subscription = Some(topic.clone());
+        Ok(())
+    }
+
+    fn seek(
+        &mut self,
+        topic: &NodeStreamTopic,
+        position: &NodeStreamStartPosition,
+    ) -> Result<(), NodeStreamBackendError> {
+        for (partition, backend) in (0..).zip(&mut self.partitions) {
+            backend.seek(
+                &partition_topic(topic, partition),
+                &partition_position(position, partition),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn positions(&mut self) -> Result<Vec<NodeStreamPartitionOffset>, NodeStreamBackendError> {
+        let mut positions = vec![];
+        for (partition, backend) in (0..).zip(&mut self.partitions) {
+            positions.extend(
+                backend
+                    .positions()?
+                    .into_iter()
+                    .map(|o| NodeStreamPartitionOffset {
+                        partition,
+                        offset: o.offset,
+                    }),
+            );
+        }
+        Ok(positions)
+    }
+
+    fn poll(&mut self) -> Result<Vec<NodeStreamFetchedRecord>, NodeStreamBackendError> {
+        let topic = self
+            .subscription
+            .clone()
+            .ok_or(NodeStreamBackendError::NotSubscribed)?;
+        let partition = self.next_poll % self.partitions.len();
+        self.next_poll = partition + 1;
+        let partition = partition as i32;
+        if self.held.lock().unwrap().contains(&partition) {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .partition(partition)?
+            .poll()?
+            .into_iter()
+            .map(|record| NodeStreamFetchedRecord {
+                topic: topic.clone(),
+                partition,
+                ..record
+            })
+            .collect())
+    }
+
+    fn commit(
+        &mut self,
+        topic: &NodeStreamTopic,
+        offsets: &[NodeStreamPartitionOffset],
+    ) -> Result<(), NodeStreamBackendError> {
+        for o in offsets {
+            self.partition(o.partition)?.commit(
+                &partition_topic(topic, o.partition),
+                &[NodeStreamPartitionOffset {
+                    partition: 0,
+                    offset: o.offset,
+                }],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn topic_metadata(
+        &mut self,
+        topic: &NodeStreamTopic,
+    ) -> Result<Option<NodeStreamTopicMetadata>, NodeStreamBackendError> {
+        let mut partitions = vec![];
+        for (partition, backend) in (0..).zip(&mut self.partitions) {
+            let metadata = match backend.topic_metadata(&partition_topic(topic, partition))? {
+                Some(metadata) => metadata,
+                None => return Ok(None),
+            };
+            partitions.extend(
+                metadata
+                    .partitions
+                    .into_iter()
+                    .map(|p| NodeStreamPartitionMetadata { partition, ..p }),
+            );
+        }
+        Ok(Some(NodeStreamTopicMetadata {
+            topic: topic.clone(),
+            partitions,
+        }))
+    }
+
+    fn create_topic(
+        &mut self,
+        topic: &NodeStreamTopic,
+        _config: &NodeStreamTopicConfig,
+    ) -> Result<(), NodeStreamBackendError> {
+        for (partition, backend) in (0..).zip(&mut self.partitions) {
+            backend.create_topic(&partition_topic(topic, partition), &Default::default())?;
+        }
+        Ok(())
+    }
+
+    fn delete_topic(&mut self, topic: &NodeStreamTopic) -> Result<(), NodeStreamBackendError> {
+        for (partition, backend) in (0..).zip(&mut self.partitions) {
+            backend.delete_topic(&partition_topic(topic, partition))?;
+        }
+        Ok(())
+    }
+
+    fn list_topics(&mut self) -> Result<Vec<NodeStreamTopic>, NodeStreamBackendError> {
+        Err(NodeStreamBackendError::Unsupported {
+            operation: "listing partitioned topics".to_string(),
+        })
+    }
+}
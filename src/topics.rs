@@ -0,0 +1,188 @@
+use crate::codec::{BcsCodec, CborCodec, JsonCodec, NodeStreamCodec, NodeStreamCodecId};
+use crate::compression::NodeStreamCompression;
+use crate::types::{
+    NodeStreamPerEpochTopic, NodeStreamTopic, NodeStreamTopicName, NodeStreamUserPayload,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+/// How a topic family names the topic of each epoch.
+pub trait NodeStreamTopicNaming {
+    fn topic_for_epoch(&self, epoch: u64) -> NodeStreamTopic;
+
+    /// Inverse of `topic_for_epoch`, `None` if `topic` is not one of the family's.
+    fn epoch_for_topic(&self, topic: &NodeStreamTopic) -> Option<u64>;
+}
+
+/// Names each epoch's topic `<prefix><epoch>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixNaming {
+    prefix: String,
+}
+
+impl PrefixNaming {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl NodeStreamTopicNaming for PrefixNaming {
+    fn topic_for_epoch(&self, epoch: u64) -> NodeStreamTopic {
+        NodeStreamTopic::new(format!("{}{}", self.prefix, epoch))
+    }
+
+    fn epoch_for_topic(&self, topic: &NodeStreamTopic) -> Option<u64> {
+        let epoch = topic.topic.strip_prefix(&self.prefix)?.parse().ok()?;
+        (self.topic_for_epoch(epoch) == *topic).then_some(epoch)
+    }
+}
+
+/// Uses the structured name with each epoch in turn, ignoring its own epoch.
+impl NodeStreamTopicNaming for NodeStreamTopicName {
+    fn topic_for_epoch(&self, epoch: u64) -> NodeStreamTopic {
+        self.with_epoch(epoch).to_topic()
+    }
+
+    fn epoch_for_topic(&self, topic: &NodeStreamTopic) -> Option<u64> {
+        self.epoch_of(topic)
+    }
+}
+
+/// Topic family combining a naming scheme `N` with a payload codec `C`, for
+/// any serde data and metadata types.
+pub struct CodecTopic<N, C, D, M> {
+    naming: N,
+    schema_version: u32,
+    compression: Option<NodeStreamCompression>,
+    key: Option<fn(&D, &M) -> Vec<u8>>,
+    codec: PhantomData<fn() -> C>,
+    payload: PhantomData<fn() -> (D, M)>,
+}
+
+pub type BcsTopic<D, M, N = PrefixNaming> = CodecTopic<N, BcsCodec, D, M>;
+pub type JsonTopic<D, M, N = PrefixNaming> = CodecTopic<N, JsonCodec, D, M>;
+pub type CborTopic<D, M, N = PrefixNaming> = CodecTopic<N, CborCodec, D, M>;
+
+impl<C, D, M> CodecTopic<PrefixNaming, C, D, M> {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self::with_naming(PrefixNaming::new(prefix))
+    }
+}
+
+impl<N, C, D, M> CodecTopic<N, C, D, M> {
+    pub fn with_naming(naming: N) -> Self {
+        Self {
+            naming,
+            schema_version: 0,
+            compression: None,
+            key: None,
+            codec: PhantomData,
+            payload: PhantomData,
+        }
+    }
+
+    /// Sets the schema version producers write in envelopes. Consumers
+    /// reject records written with a newer one.
+    pub fn with_schema_version(self, schema_version: u32) -> Self {
+        Self {
+            schema_version,
+            ..self
+        }
+    }
+
+    pub fn with_compression(self, compression: NodeStreamCompression) -> Self {
+        Self {
+            compression: Some(compression),
+            ..self
+        }
+    }
+
+    /// Derives the key payloads are published with from their data and
+    /// metadata, see `NodeStreamPerEpochTopic::payload_key`.
+    pub fn with_key(self, key: fn(&D, &M) -> Vec<u8>) -> Self {
+        Self {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    pub fn naming(&self) -> &N {
+        &self.naming
+    }
+}
+
+impl<N: Clone, C, D, M> Clone for CodecTopic<N, C, D, M> {
+    fn clone(&self) -> Self {
+        Self {
+            naming: self.naming.clone(),
+            schema_version: self.schema_version,
+            compression: self.compression,
+            key: self.key,
+            codec: PhantomData,
+            payload: PhantomData,
+        }
+    }
+}
+
+impl<N: std::fmt::Debug, C, D, M> std::fmt::Debug for CodecTopic<N, C, D, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodecTopic")
+            .field("naming", &self.naming)
+            .field("schema_version", &self.schema_version)
+            .field("compression", &self.compression)
+            .field("keyed", &self.key.is_some())
+            .field("codec", &std::any::type_name::<C>())
+            .finish()
+    }
+}
+
+impl<N, C, D, M> NodeStreamPerEpochTopic<D, M> for CodecTopic<N, C, D, M>
+where
+    N: NodeStreamTopicNaming,
+    C: NodeStreamCodec,
+    D: Serialize + DeserializeOwned + std::fmt::Debug,
+    M: Serialize + DeserializeOwned + std::fmt::Debug,
+{
+    type FromBytesError = C::DecodeError;
+    type ToBytesError = C::EncodeError;
+
+    fn topic_for_epoch(&self, epoch: u64) -> NodeStreamTopic {
+        self.naming.topic_for_epoch(epoch)
+    }
+
+    fn epoch_for_topic(&self, topic: &NodeStreamTopic) -> Option<u64> {
+        self.naming.epoch_for_topic(topic)
+    }
+
+    fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    fn codec_id(&self) -> NodeStreamCodecId {
+        C::ID
+    }
+
+    fn compression(&self) -> Option<NodeStreamCompression> {
+        self.compression
+    }
+
+    fn payload_key(&self, payload: &NodeStreamUserPayload<D, M>) -> Option<Vec<u8>> {
+        self.key.map(|key| key(&payload.data, &payload.metdata))
+    }
+
+    fn payload_from_bytes(
+        &self,
+        bytes: &[u8],
+    ) -> Result<NodeStreamUserPayload<D, M>, Self::FromBytesError> {
+        C::decode(bytes)
+    }
+
+    fn payload_to_bytes(
+        &self,
+        payload: &NodeStreamUserPayload<D, M>,
+    ) -> Result<Vec<u8>, Self::ToBytesError> {
+        C::encode(payload)
+    }
+}
@@ -1,3 +1,8 @@
+use crate::ack::NodeStreamAckHandle;
+use crate::codec::NodeStreamCodecId;
+use crate::compression::NodeStreamCompression;
+use crate::envelope::{NodeStreamChecksumAlgorithm, NodeStreamEnvelopeHeader};
+use crate::signature::NodeStreamSignatureStatus;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -6,13 +11,227 @@ use std::{
 };
 use thiserror::Error as Error1;
 
+// ================= Backend Errors ===========================
+
+/// The transport error behind a backend failure, e.g. a `kafka::Error`.
+pub type NodeStreamBackendSource = Box<dyn std::error::Error + Send + Sync>;
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error1)]
+pub enum NodeStreamBackendError {
+    #[error("UnableToConnect: unable to connect to backend, err: {:?}", err)]
+    UnableToConnect { err: NodeStreamBackendSource },
+
+    #[error("NotSubscribed: backend has no active subscription")]
+    NotSubscribed,
+
+    #[error("Unsupported: backend does not support {}", operation)]
+    Unsupported { operation: String },
+
+    #[error("InvalidTopicName: topic name not supported by backend: {}", topic)]
+    InvalidTopicName { topic: NodeStreamTopic },
+
+    #[error("InvalidGroupId: group id not supported by backend: {:?}", group_id)]
+    InvalidGroupId { group_id: String },
+
+    #[error(
+        "TopicNotCreated: topic: {} was not created, is topic auto-creation enabled on the broker?",
+        topic
+    )]
+    TopicNotCreated { topic: NodeStreamTopic },
+
+    #[error("Io: backend io error, err: {}", err)]
+    Io { err: std::io::Error },
+
+    #[error("TopicLocked: topic: {} is being written by another backend", topic)]
+    TopicLocked { topic: NodeStreamTopic },
+
+    #[error("CorruptLog: log for topic: {} is corrupt, reason: {}", topic, reason)]
+    CorruptLog {
+        topic: NodeStreamTopic,
+        reason: String,
+    },
+
+    #[error(
+        "UnableToLoadMetadata: unable to load metadata for topic: {:?}, err: {}",
+        topic,
+        err
+    )]
+    UnableToLoadMetadata {
+        topic: Option<NodeStreamTopic>,
+        err: NodeStreamBackendSource,
+    },
+
+    #[error("UnableToPublish: unable to publish to topic: {}, err: {}", topic, err)]
+    UnableToPublish {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendSource,
+    },
+
+    #[error(
+        "UnconfirmedDelivery: broker did not confirm records sent to partition: {} of topic: {}",
+        partition,
+        topic
+    )]
+    UnconfirmedDelivery {
+        topic: NodeStreamTopic,
+        partition: i32,
+    },
+
+    #[error(
+        "TopicRequestRejected: broker rejected request for topic: {}, error code: {}, message: {:?}",
+        topic,
+        error_code,
+        message
+    )]
+    TopicRequestRejected {
+        topic: NodeStreamTopic,
+        error_code: i16,
+        message: Option<String>,
+    },
+
+    #[error("UnableToPoll: unable to poll, err: {:?}", err)]
+    UnableToPoll { err: NodeStreamBackendSource },
+
+    #[error(
+        "UnableToCommit: unable to commit offsets for topic: {}, err: {:?}",
+        topic,
+        err
+    )]
+    UnableToCommit {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendSource,
+    },
+}
+
+impl NodeStreamBackendError {
+    /// Whether the error may go away by trying again, e.g. a broker being
+    /// unreachable or a partition changing leader. Errors caused by the
+    /// request itself, such as an invalid topic name, are fatal.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::UnableToConnect { err }
+            | Self::UnableToLoadMetadata { err, .. }
+            | Self::UnableToPublish { err, .. }
+            | Self::UnableToPoll { err }
+            | Self::UnableToCommit { err, .. } => is_retryable_source(err.as_ref()),
+            Self::TopicRequestRejected { error_code, .. } => {
+                crate::kafka_admin::is_retryable_code(*error_code)
+            }
+            // The broker may still be creating it
+            Self::TopicNotCreated { .. } | Self::UnconfirmedDelivery { .. } => true,
+            Self::Io { err } => matches!(
+                err.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            Self::NotSubscribed
+            | Self::Unsupported { .. }
+            | Self::InvalidTopicName { .. }
+            | Self::InvalidGroupId { .. }
+            | Self::TopicLocked { .. }
+            | Self::CorruptLog { .. } => false,
+        }
+    }
+
+    /// Copy of `self` for reporting the same failure to several callers.
+    /// Sources are copied as their message, and stay retryable if they were.
+    pub(crate) fn duplicate(&self) -> Self {
+        let source = |err: &NodeStreamBackendSource| -> NodeStreamBackendSource {
+            Box::new(DuplicatedSource {
+                message: err.to_string(),
+                retryable: is_retryable_source(err.as_ref()),
+            })
+        };
+        match self {
+            Self::UnableToConnect { err } => Self::UnableToConnect { err: source(err) },
+            Self::NotSubscribed => Self::NotSubscribed,
+            Self::Unsupported { operation } => Self::Unsupported {
+                operation: operation.clone(),
+            },
+            Self::InvalidTopicName { topic } => Self::InvalidTopicName {
+                topic: topic.clone(),
+            },
+            Self::InvalidGroupId { group_id } => Self::InvalidGroupId {
+                group_id: group_id.clone(),
+            },
+            Self::TopicNotCreated { topic } => Self::TopicNotCreated {
+                topic: topic.clone(),
+            },
+            Self::Io { err } => Self::Io {
+                err: std::io::Error::new(err.kind(), err.to_string()),
+            },
+            Self::TopicLocked { topic } => Self::TopicLocked {
+                topic: topic.clone(),
+            },
+            Self::CorruptLog { topic, reason } => Self::CorruptLog {
+                topic: topic.clone(),
+                reason: reason.clone(),
+            },
+            Self::UnableToLoadMetadata { topic, err } => Self::UnableToLoadMetadata {
+                topic: topic.clone(),
+                err: source(err),
+            },
+            Self::UnableToPublish { topic, err } => Self::UnableToPublish {
+                topic: topic.clone(),
+                err: source(err),
+            },
+            Self::UnconfirmedDelivery { topic, partition } => Self::UnconfirmedDelivery {
+                topic: topic.clone(),
+                partition: *partition,
+            },
+            Self::TopicRequestRejected {
+                topic,
+                error_code,
+                message,
+            } => Self::TopicRequestRejected {
+                topic: topic.clone(),
+                error_code: *error_code,
+                message: message.clone(),
+            },
+            Self::UnableToPoll { err } => Self::UnableToPoll { err: source(err) },
+            Self::UnableToCommit { topic, err } => Self::UnableToCommit {
+                topic: topic.clone(),
+                err: source(err),
+            },
+        }
+    }
+}
+
+/// Source of a `NodeStreamBackendError::duplicate` copy.
+#[derive(Debug)]
+struct DuplicatedSource {
+    message: String,
+    retryable: bool,
+}
+
+impl std::fmt::Display for DuplicatedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DuplicatedSource {}
+
+fn is_retryable_source(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<kafka::Error>() {
+        return crate::backend::is_retryable_kafka_error(err);
+    }
+    if let Some(err) = err.downcast_ref::<DuplicatedSource>() {
+        return err.retryable;
+    }
+    // Like Kafka's own, other transports' io errors are connection failures
+    err.is::<std::io::Error>()
+}
+
 // ================= Consumer Errors ===========================
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Error1)]
 pub enum NodeStreamConsumerError {
-    #[error("UnableToCreteConsumer: {:?}", err)]
-    UnableToCreateConsumer { err: kafka::Error },
+    #[error("UnableToCreteConsumer: {}", err)]
+    UnableToCreateConsumer { err: NodeStreamBackendError },
 
     #[error("DuplicateSession: old: {}, new: {}", old, new)]
     DuplicateSession {
@@ -28,32 +247,214 @@ pub enum NodeStreamConsumerError {
     PayloadDeserializeError { topic: NodeStreamTopic, err: String },
 
     #[error(
-        "UnableToPollMessage: unable to poll for topic: {}, err: {:?}",
+        "UnableToPollMessage: unable to poll for topic: {}, err: {}",
         topic,
         err
     )]
     UnableToPollMessage {
         topic: NodeStreamTopic,
-        err: kafka::Error,
+        err: NodeStreamBackendError,
+    },
+
+    #[error("UnableToSeek: unable to seek on topic: {}, err: {}", topic, err)]
+    UnableToSeek {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendError,
+    },
+
+    #[error(
+        "UnableToRollOverEpoch: unable to move from epoch: {} to the next one, err: {}",
+        epoch,
+        err
+    )]
+    UnableToRollOverEpoch {
+        epoch: u64,
+        err: NodeStreamBackendError,
     },
 
     #[error(
-        "UnableToMarkMessageConsumed: unable to mark message consumed for topic: {}, err: {:?}",
+        "UnableToCommitMessageConsumed: unable to commit message consumed for topic: {}, err: {}",
         topic,
         err
     )]
-    UnableToMarkMessageConsumed {
+    UnableToCommitMessageConsumed {
         topic: NodeStreamTopic,
-        err: kafka::Error,
+        err: NodeStreamBackendError,
     },
+
     #[error(
-        "UnableToCommitMessageConsumed: unable to commit message consumed for topic: {}, err: {:?}",
+        "InvalidEnvelope: invalid envelope at offset: {} of topic: {}, err: {}",
+        offset,
         topic,
         err
     )]
-    UnableToCommitMessageConsumed {
+    InvalidEnvelope {
+        topic: NodeStreamTopic,
+        offset: i64,
+        err: NodeStreamEnvelopeError,
+    },
+
+    #[error(
+        "ChecksumMismatch: {:?} checksum does not match the payload at offset: {} of topic: {}",
+        algorithm,
+        offset,
+        topic
+    )]
+    ChecksumMismatch {
+        topic: NodeStreamTopic,
+        offset: i64,
+        algorithm: NodeStreamChecksumAlgorithm,
+    },
+
+    #[error(
+        "InvalidSignature: message at offset: {} of topic: {} is not signed by a trusted signer, status: {:?}",
+        offset,
+        topic,
+        status
+    )]
+    InvalidSignature {
+        topic: NodeStreamTopic,
+        offset: i64,
+        status: NodeStreamSignatureStatus,
+    },
+
+    #[error(
+        "UnableToDecrypt: unable to decrypt message at offset: {} of topic: {}, err: {}",
+        offset,
+        topic,
+        err
+    )]
+    UnableToDecrypt {
+        topic: NodeStreamTopic,
+        offset: i64,
+        err: String,
+    },
+
+    #[error(
+        "UnableToDecompress: unable to decompress message at offset: {} of topic: {}, err: {}",
+        offset,
+        topic,
+        err
+    )]
+    UnableToDecompress {
         topic: NodeStreamTopic,
-        err: kafka::Error,
+        offset: i64,
+        err: String,
+    },
+
+    #[error(
+        "InvalidChunk: invalid chunk at offset: {} of topic: {}, err: {}",
+        offset,
+        topic,
+        err
+    )]
+    InvalidChunk {
+        topic: NodeStreamTopic,
+        offset: i64,
+        err: String,
+    },
+
+    #[error("UnableToDiscoverEpochs: unable to list epoch topics, err: {}", err)]
+    UnableToDiscoverEpochs { err: NodeStreamBackendError },
+
+    #[error("NoEpochAvailable: no epoch of the topic family has a topic")]
+    NoEpochAvailable,
+
+    #[error(
+        "UnableToDeadLetter: unable to forward message at offset: {} of topic: {} to its dead-letter topic, err: {}",
+        offset,
+        topic,
+        err
+    )]
+    UnableToDeadLetter {
+        topic: NodeStreamTopic,
+        offset: i64,
+        err: NodeStreamBackendError,
+    },
+}
+
+impl NodeStreamConsumerError {
+    /// Whether the error comes from a backend error which may go away by
+    /// trying again. Errors about the records themselves are fatal.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::UnableToCreateConsumer { err }
+            | Self::UnableToPollMessage { err, .. }
+            | Self::UnableToSeek { err, .. }
+            | Self::UnableToRollOverEpoch { err, .. }
+            | Self::UnableToCommitMessageConsumed { err, .. }
+            | Self::UnableToDiscoverEpochs { err }
+            | Self::UnableToDeadLetter { err, .. } => err.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+// ================= Envelope Errors ===========================
+
+#[derive(Debug, Error1, PartialEq, Eq)]
+pub enum NodeStreamEnvelopeError {
+    #[error("Malformed: unable to decode envelope, err: {}", err)]
+    Malformed { err: String },
+
+    #[error("UnsupportedVersion: envelope version {} is not supported", version)]
+    UnsupportedVersion { version: u8 },
+
+    #[error(
+        "CodecMismatch: record written with codec {:?}, expected {:?}",
+        found,
+        expected
+    )]
+    CodecMismatch {
+        expected: NodeStreamCodecId,
+        found: NodeStreamCodecId,
+    },
+
+    #[error(
+        "UnsupportedSchemaVersion: record written with schema version {}, newest supported is {}",
+        found,
+        supported
+    )]
+    UnsupportedSchemaVersion { supported: u32, found: u32 },
+
+    #[error("LegacyRecord: record has no envelope and legacy records are not accepted")]
+    LegacyRecord,
+
+    #[error("MissingChecksum: record has no checksum and checksums are required")]
+    MissingChecksum,
+
+    #[error("NotEncrypted: record is not encrypted and encryption is required")]
+    NotEncrypted,
+}
+
+// ================= Admin Errors ===========================
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Error1)]
+pub enum NodeStreamAdminError {
+    #[error("UnableToCreateTopic: unable to create topic: {}, err: {}", topic, err)]
+    UnableToCreateTopic {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendError,
+    },
+
+    #[error(
+        "UnableToDescribeTopic: unable to describe topic: {}, err: {}",
+        topic,
+        err
+    )]
+    UnableToDescribeTopic {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendError,
+    },
+
+    #[error("UnableToListTopics: unable to list topics, err: {}", err)]
+    UnableToListTopics { err: NodeStreamBackendError },
+
+    #[error("UnableToDeleteTopic: unable to delete topic: {}, err: {}", topic, err)]
+    UnableToDeleteTopic {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendError,
     },
 }
 
@@ -63,7 +464,7 @@ pub enum NodeStreamConsumerError {
 #[derive(Debug, Error1)]
 pub enum NodeStreamProducerError {
     #[error("UnableToCreateProducer: {}", err)]
-    UnableToCreateProducer { err: kafka::Error },
+    UnableToCreateProducer { err: NodeStreamBackendError },
 
     #[error(
         "PayloadSerializeError: unable to serialize payload for topic: {}, err: {}",
@@ -72,32 +473,90 @@ pub enum NodeStreamProducerError {
     )]
     PayloadSerializeError { topic: NodeStreamTopic, err: String },
 
+    #[error("PayloadTooLarge: payload too large. Limit: {} vs {}", limit, size)]
+    PayloadTooLarge { limit: u64, size: u64 },
+
+    #[error("MessageSendFailed: unable to send message, err: {}", err)]
+    MessageSendFailed { err: NodeStreamBackendError },
+
     #[error(
-        "UnableToLoadAllInternalMetatada: unable to load internal metadata. err: {}",
+        "UnableToProvisionTopic: unable to create topic: {} ahead of its epoch, err: {}",
+        topic,
         err
     )]
-    UnableToLoadAllInternalMetatada { err: kafka::Error },
+    UnableToProvisionTopic {
+        topic: NodeStreamTopic,
+        err: NodeStreamBackendError,
+    },
 
     #[error(
-        "UnableToLoadTopicInternalMetatada: unable to load internal metadata for topic: {}, err: {}",       
+        "UnableToEncrypt: unable to encrypt payload for topic: {}, err: {}",
         topic,
         err
     )]
-    UnableToLoadTopicInternalMetatada {
-        topic: NodeStreamTopic,
-        err: kafka::Error,
-    },
+    UnableToEncrypt { topic: NodeStreamTopic, err: String },
 
-    #[error("PayloadTooLarge: payload too large. Limit: {} vs {}", limit, size)]
-    PayloadTooLarge { limit: u64, size: u64 },
+    #[error(
+        "UnableToCompress: unable to compress payload for topic: {}, err: {}",
+        topic,
+        err
+    )]
+    UnableToCompress { topic: NodeStreamTopic, err: String },
 
-    #[error("MessageSendFailed: unable to send message, err: {}", err)]
-    MessageSendFailed { err: kafka::Error },
+    #[error("QueueFull: producer queue is full")]
+    QueueFull,
+
+    #[error("ProducerClosed: producer background task is no longer running")]
+    ProducerClosed,
+}
+
+impl NodeStreamProducerError {
+    /// Whether sending again may succeed. Payloads which cannot be
+    /// serialized, compressed, encrypted or are too large never will.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::UnableToCreateProducer { err }
+            | Self::MessageSendFailed { err }
+            | Self::UnableToProvisionTopic { err, .. } => err.is_retryable(),
+            Self::QueueFull => true,
+            _ => false,
+        }
+    }
+}
+
+// ================= Topic Name Errors ===========================
+
+#[derive(Debug, Error1, PartialEq, Eq)]
+pub enum NodeStreamTopicNameError {
+    #[error("TooLong: topic name is {} characters long, limit is {}", len, limit)]
+    TooLong { len: usize, limit: usize },
+
+    #[error(
+        "InvalidCharacters: topic name: {:?} may only use [a-zA-Z0-9._-]",
+        name
+    )]
+    InvalidCharacters { name: String },
+
+    #[error(
+        "InvalidSegment: {} segment: {:?} must be non-empty and use [a-zA-Z0-9_-]",
+        segment,
+        value
+    )]
+    InvalidSegment { segment: String, value: String },
+
+    #[error(
+        "Malformed: topic name: {:?} is not <namespace>.<network>.<stream>.e<epoch>[.v<version>]",
+        name
+    )]
+    Malformed { name: String },
 }
 
 // ================= Topic ===========================
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Longest topic name Kafka accepts.
+pub const TOPIC_NAME_MAX_LEN: usize = 249;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct NodeStreamTopic {
     pub(crate) topic: String,
 }
@@ -118,13 +577,259 @@ impl NodeStreamTopic {
     pub fn to_raw(&self) -> String {
         self.topic.clone()
     }
+
+    /// Checks the name against Kafka's topic naming rules.
+    pub fn validate(&self) -> Result<(), NodeStreamTopicNameError> {
+        if self.topic.len() > TOPIC_NAME_MAX_LEN {
+            return Err(NodeStreamTopicNameError::TooLong {
+                len: self.topic.len(),
+                limit: TOPIC_NAME_MAX_LEN,
+            });
+        }
+        let valid = !self.topic.is_empty()
+            && self.topic != "."
+            && self.topic != ".."
+            && self
+                .topic
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+        if !valid {
+            return Err(NodeStreamTopicNameError::InvalidCharacters {
+                name: self.topic.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Topic receiving the messages of this topic which consumers could not
+    /// deserialize, as `NodeStreamDeadLetter` records.
+    pub fn dead_letter(&self) -> NodeStreamTopic {
+        NodeStreamTopic {
+            topic: format!("{}.dead-letter", self.topic),
+        }
+    }
+}
+
+/// Structured topic name, written `<namespace>.<network>.<stream>.e<epoch>`
+/// with an optional `.v<version>` suffix, e.g. `sui.mainnet.checkpoints.e42.v1`.
+/// Keeping the network in the name lets several networks share a cluster.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeStreamTopicName {
+    namespace: String,
+    network: String,
+    stream: String,
+    epoch: u64,
+    version: Option<u32>,
+}
+
+impl NodeStreamTopicName {
+    /// Fails if a segment is empty or uses characters other than
+    /// `[a-zA-Z0-9_-]`, or if the name could exceed Kafka's length limit with
+    /// the largest epoch and version.
+    pub fn new(
+        namespace: &str,
+        network: &str,
+        stream: &str,
+        epoch: u64,
+    ) -> Result<Self, NodeStreamTopicNameError> {
+        for (segment, value) in [
+            ("namespace", namespace),
+            ("network", network),
+            ("stream", stream),
+        ] {
+            let valid = !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !valid {
+                return Err(NodeStreamTopicNameError::InvalidSegment {
+                    segment: segment.to_string(),
+                    value: value.to_string(),
+                });
+            }
+        }
+        let name = Self {
+            namespace: namespace.to_string(),
+            network: network.to_string(),
+            stream: stream.to_string(),
+            epoch,
+            version: None,
+        };
+        let longest = name
+            .with_epoch(u64::MAX)
+            .with_version(u32::MAX)
+            .to_string()
+            .len();
+        if longest > TOPIC_NAME_MAX_LEN {
+            return Err(NodeStreamTopicNameError::TooLong {
+                len: longest,
+                limit: TOPIC_NAME_MAX_LEN,
+            });
+        }
+        Ok(name)
+    }
+
+    pub fn with_epoch(&self, epoch: u64) -> Self {
+        Self {
+            epoch,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_version(&self, version: u32) -> Self {
+        Self {
+            version: Some(version),
+            ..self.clone()
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn to_topic(&self) -> NodeStreamTopic {
+        NodeStreamTopic::new(self.to_string())
+    }
+
+    /// Epoch of `topic` if its name only differs from `self` by the epoch,
+    /// which makes this the inverse of `with_epoch(..).to_topic()`.
+    pub fn epoch_of(&self, topic: &NodeStreamTopic) -> Option<u64> {
+        let name = NodeStreamTopicName::try_from(topic).ok()?;
+        (name.with_epoch(self.epoch) == *self).then_some(name.epoch)
+    }
+}
+
+impl Display for NodeStreamTopicName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.e{}",
+            self.namespace, self.network, self.stream, self.epoch
+        )?;
+        if let Some(version) = self.version {
+            write!(f, ".v{}", version)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for NodeStreamTopicName {
+    type Err = NodeStreamTopicNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NodeStreamTopic::new(s.to_string()).validate()?;
+        let malformed = || NodeStreamTopicNameError::Malformed {
+            name: s.to_string(),
+        };
+        // Numbers are written without leading zeros, so only accept that
+        // spelling to keep parsing and formatting round-trippable
+        fn number<N: FromStr>(segment: &str, prefix: char) -> Option<N> {
+            let digits = segment.strip_prefix(prefix)?;
+            if !digits.chars().all(|c| c.is_ascii_digit())
+                || (digits.len() > 1 && digits.starts_with('0'))
+            {
+                return None;
+            }
+            digits.parse().ok()
+        }
+        let segments = s.split('.').collect::<Vec<_>>();
+        let (epoch, version) = match segments[..] {
+            [_, _, _, epoch] => (number(epoch, 'e'), None),
+            [_, _, _, epoch, version] => (
+                number(epoch, 'e'),
+                Some(number(version, 'v').ok_or_else(malformed)?),
+            ),
+            _ => return Err(malformed()),
+        };
+        let name = Self::new(
+            segments[0],
+            segments[1],
+            segments[2],
+            epoch.ok_or_else(malformed)?,
+        )?;
+        Ok(match version {
+            Some(version) => name.with_version(version),
+            None => name,
+        })
+    }
 }
 
+impl TryFrom<&NodeStreamTopic> for NodeStreamTopicName {
+    type Error = NodeStreamTopicNameError;
+
+    fn try_from(topic: &NodeStreamTopic) -> Result<Self, Self::Error> {
+        topic.topic.parse()
+    }
+}
+
+/// Record published by `NodeStreamProducer::send_epoch_end` to tell consumers
+/// nothing else will be written to an epoch's topic. Consumers never surface it.
+pub const EPOCH_END_MARKER: &[u8] = b"\0node-stream/epoch-end\0";
+
 pub trait NodeStreamPerEpochTopic<D: std::fmt::Debug, M: std::fmt::Debug> {
     type FromBytesError: std::fmt::Debug;
     type ToBytesError: std::fmt::Debug;
 
     fn topic_for_epoch(&self, epoch: u64) -> NodeStreamTopic;
+
+    /// Inverse of `topic_for_epoch`, `None` if `topic` is not one of this
+    /// family's topics. The default handles names ending with the epoch
+    /// number, trying every split of the trailing digits since the rest of
+    /// the name may end with digits too; naming schemes which put the epoch
+    /// elsewhere must override it.
+    fn epoch_for_topic(&self, topic: &NodeStreamTopic) -> Option<u64> {
+        let name = &topic.topic;
+        let digits = name.len() - name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        // Splits giving an epoch with leading zeros never round-trip
+        (1..=digits).find_map(|len| {
+            let epoch = name[name.len() - len..].parse().ok()?;
+            (self.topic_for_epoch(epoch) == *topic).then_some(epoch)
+        })
+    }
+
+    /// Schema version written in envelopes. Consumers reject records written
+    /// with a newer schema version than theirs.
+    fn schema_version(&self) -> u32 {
+        0
+    }
+
+    /// Codec written in envelopes. Consumers reject records written with
+    /// another codec, unless either side leaves it unspecified.
+    fn codec_id(&self) -> NodeStreamCodecId {
+        NodeStreamCodecId::UNSPECIFIED
+    }
+
+    /// Compression producers apply to serialized payloads. Consumers
+    /// decompress whatever the envelope says.
+    fn compression(&self) -> Option<NodeStreamCompression> {
+        None
+    }
+
+    /// Key `payload` is published with, e.g. a sender address or object id.
+    /// Records with the same key go to the same partition, so they stay in
+    /// order. Without a key the backend picks the partition, except for
+    /// payloads split into chunks: those are keyed by their message id, so
+    /// their chunks still share a partition.
+    fn payload_key(&self, _payload: &NodeStreamUserPayload<D, M>) -> Option<Vec<u8>> {
+        None
+    }
+
     fn payload_from_bytes(
         &self,
         bytes: &[u8],
@@ -220,7 +925,146 @@ pub struct NodeStreamUserPayload<DataType: Debug, MetadataType: Debug> {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeStreamMessage<DataType: Debug, MetadataType: Debug> {
     // Metadata
+    pub epoch: u64,
     pub message_offset: i64,
     // Content
     pub payload: NodeStreamUserPayload<DataType, MetadataType>,
+    // Not set for legacy records written without an envelope
+    #[serde(default)]
+    pub envelope: Option<NodeStreamEnvelopeHeader>,
+    // Only checked if the consumer is configured to
+    #[serde(default)]
+    pub signature: NodeStreamSignatureStatus,
+    // Only set by consumers in explicit ack mode
+    #[serde(skip)]
+    pub ack: Option<NodeStreamAckHandle>,
+}
+
+impl<DataType: Debug, MetadataType: Debug> NodeStreamMessage<DataType, MetadataType> {
+    /// Acks the message if it was read in explicit ack mode, does nothing otherwise.
+    pub fn ack(&self) {
+        if let Some(ack) = &self.ack {
+            ack.ack();
+        }
+    }
+}
+
+/// Message a consumer could not deserialize, as forwarded to the dead-letter
+/// topic of the topic it was read from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStreamDeadLetter {
+    pub topic: NodeStreamTopic,
+    pub epoch: u64,
+    pub partition: i32,
+    pub offset: i64,
+    pub error: String,
+    // Raw record as read from the topic
+    pub payload: Vec<u8>,
+}
+
+impl NodeStreamDeadLetter {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bcs::Error> {
+        bcs::to_bytes(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bcs::Error> {
+        bcs::from_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct VersionedTopic;
+
+    impl NodeStreamPerEpochTopic<u64, u64> for VersionedTopic {
+        type FromBytesError = bcs::Error;
+        type ToBytesError = bcs::Error;
+
+        fn topic_for_epoch(&self, epoch: u64) -> NodeStreamTopic {
+            NodeStreamTopic::new(format!("stream-v2{}", epoch))
+        }
+
+        fn payload_from_bytes(
+            &self,
+            bytes: &[u8],
+        ) -> Result<NodeStreamUserPayload<u64, u64>, bcs::Error> {
+            bcs::from_bytes(bytes)
+        }
+
+        fn payload_to_bytes(
+            &self,
+            payload: &NodeStreamUserPayload<u64, u64>,
+        ) -> Result<Vec<u8>, bcs::Error> {
+            bcs::to_bytes(payload)
+        }
+    }
+
+    #[test]
+    fn transport_errors_are_retried_by_their_cause() {
+        let poll_err = |err: NodeStreamBackendSource| NodeStreamBackendError::UnableToPoll { err };
+        assert!(poll_err(kafka::Error::NoHostReachable.into()).is_retryable());
+        assert!(
+            poll_err(kafka::Error::Kafka(kafka::error::KafkaCode::LeaderNotAvailable).into())
+                .is_retryable()
+        );
+        assert!(!poll_err(kafka::Error::UnsupportedProtocol.into()).is_retryable());
+        assert!(
+            poll_err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+                .is_retryable()
+        );
+        assert!(!poll_err("unknown transport error".into()).is_retryable());
+    }
+
+    #[test]
+    fn epochs_are_found_after_prefixes_ending_with_digits() {
+        for epoch in [0, 7, 20, 123] {
+            let topic = VersionedTopic.topic_for_epoch(epoch);
+            assert_eq!(VersionedTopic.epoch_for_topic(&topic), Some(epoch));
+        }
+        for name in ["stream-v2", "stream-v3", "stream-v207", "other-v21"] {
+            let topic = NodeStreamTopic::new(name.to_string());
+            assert_eq!(VersionedTopic.epoch_for_topic(&topic), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn topic_names_round_trip() {
+        let name = NodeStreamTopicName::new("sui", "mainnet", "checkpoints", 42).unwrap();
+        for name in [name.clone(), name.with_version(1), name.with_epoch(0)] {
+            let topic = name.to_topic();
+            assert_eq!(NodeStreamTopicName::try_from(&topic), Ok(name.clone()));
+            assert_eq!(name.epoch_of(&topic), Some(name.epoch()));
+        }
+        assert_eq!(
+            name.with_version(1).to_string(),
+            "sui.mainnet.checkpoints.e42.v1"
+        );
+        assert_eq!(name.epoch_of(&name.with_version(1).to_topic()), None);
+    }
+
+    #[test]
+    fn topic_names_reject_malformed_numbers() {
+        for name in [
+            "sui.mainnet.checkpoints.e042",
+            "sui.mainnet.checkpoints.e42.v01",
+            "sui.mainnet.checkpoints.e",
+            "sui.mainnet.checkpoints.e4a",
+            "sui.mainnet.checkpoints.42",
+            "sui.mainnet.e42",
+        ] {
+            assert_eq!(
+                name.parse::<NodeStreamTopicName>(),
+                Err(NodeStreamTopicNameError::Malformed {
+                    name: name.to_string()
+                }),
+                "{}",
+                name
+            );
+        }
+        assert!("sui.mainnet.checkpoints.e0.v0"
+            .parse::<NodeStreamTopicName>()
+            .is_ok());
+    }
 }